@@ -2,197 +2,6107 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::de::DeserializeOwned;
 use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "schema")]
+mod schema;
+
+/// Emits a directive to Cargo, e.g. `rustc-cfg=has_fpu`.
+///
+/// Every directive from this crate goes through here.  When debugging a build
+/// script, setting `HUBRIS_BUILD_UTIL_DEBUG=1` turns this into a dry run:
+/// directives are printed to stderr, tagged with `[build-util]`, instead of
+/// being passed to Cargo (so they don't take effect).  Cargo doesn't know to
+/// rerun build scripts when that variable changes, so touch the build script
+/// after setting it.
+fn emit(directive: &str) {
+    #[cfg(test)]
+    if tests::capture(directive) {
+        return;
+    }
+    if debug_mode() {
+        eprintln!("[build-util] cargo:{}", directive);
+    } else {
+        println!("cargo:{}", directive);
+    }
+}
+
+/// Checks (once per process) whether `HUBRIS_BUILD_UTIL_DEBUG=1` is set
+fn debug_mode() -> bool {
+    static DEBUG: Mutex<Option<bool>> = Mutex::new(None);
+    *DEBUG.lock().unwrap().get_or_insert_with(|| {
+        // Deliberately not tracked with `env_var`, since that would `emit`
+        std::env::var("HUBRIS_BUILD_UTIL_DEBUG").map_or(false, |v| v == "1")
+    })
+}
+
+/// Declares every `cfg` that this crate's `expose_*` functions can set (with
+/// `cargo:rustc-check-cfg`), so that newer compilers don't warn about them
+/// with `unexpected_cfgs`.
+///
+/// This isn't called by the `expose_*` functions themselves, since the Cargo
+/// in our pinned toolchain warns about `rustc-check-cfg` unless it's run with
+/// `-Zcheck-cfg`; build scripts should call it once when they need it.  It
+/// doesn't cover names passed to `cfg_from_feature`.
+pub fn declare_custom_cfgs() {
+    for cfg in custom_cfgs() {
+        emit(&format!("rustc-check-cfg=cfg({})", cfg));
+    }
+}
+
+fn custom_cfgs() -> Vec<String> {
+    let quoted = |values: &[&str]| {
+        values
+            .iter()
+            .map(|v| format!("\"{}\"", v))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut families: Vec<&str> =
+        CHIP_FAMILIES.iter().map(|(_, f)| *f).collect();
+    families.sort_unstable();
+    families.dedup();
+    let kinds: Vec<&str> = ImageKind::ALL.iter().map(ImageKind::name).collect();
+    let formats: Vec<&str> =
+        SerFormat::ALL.iter().map(SerFormat::name).collect();
+
+    let mut out: Vec<String> = [
+        "armv6m",
+        "armv7m",
+        "armv8m",
+        "armv8m_base",
+        "armv8m_main",
+        "riscv32",
+        "has_fpu",
+        "has_dsp",
+        "has_trustzone",
+        "target_is_be",
+    ]
+    .iter()
+    .map(|c| c.to_string())
+    .collect();
+    // These take values that aren't known ahead of time (e.g. new boards)
+    for name in ["isa", "target_board", "hubris_task_count"] {
+        out.push(format!("{}, values(any())", name));
+    }
+    out.push(format!("chip_family, values({})", quoted(&families)));
+    out.push(format!("image_kind, values({})", quoted(&kinds)));
+    out.push(format!("serialization, values({})", quoted(&formats)));
+    out
+}
 
 /// Reads the given environment variable and marks that it's used
 ///
 /// This ensures a rebuild if the variable changes
 pub fn env_var(key: &str) -> Result<String> {
-    println!("cargo:rerun-if-env-changed={}", key);
-    std::env::var(key).with_context(|| format!("reading env var ${key}"))
+    read_tracked(key).with_context(|| format!("reading env var ${key}"))
 }
 
-/// Reads the `OUT_DIR` environment variable
+/// Reads the given environment variable and marks that it's used, failing if
+/// it is not set
 ///
-/// This function goes through `std::env::var` directly, rather than our own
-/// `env_var`, because Cargo should know when it changes.
-pub fn out_dir() -> std::path::PathBuf {
-    std::path::PathBuf::from(
-        std::env::var("OUT_DIR").expect("Could not get OUT_DIR"),
-    )
+/// Unlike `env_var`, the error says what's wrong in its own message, rather
+/// than wrapping a `VarError` for callers to inspect.
+pub fn env_var_required(key: &str) -> Result<String> {
+    required_value(key, read_tracked(key))
 }
 
-/// Reads the `TARGET` environment variable
+fn required_value(
+    key: &str,
+    value: Result<String, std::env::VarError>,
+) -> Result<String> {
+    match value {
+        Ok(v) => Ok(v),
+        Err(std::env::VarError::NotPresent) => {
+            bail!("required environment variable `{key}` is not set")
+        }
+        Err(std::env::VarError::NotUnicode(v)) => bail!(
+            "required environment variable `{key}` is not valid UTF-8: {v:?}"
+        ),
+    }
+}
+
+/// Reads an environment variable and marks that it's used, for all of the
+/// `env_var` family of functions
 ///
-/// This function goes through `std::env::var` directly, rather than our own
-/// `env_var`, because Cargo should know when `OUT_DIR` changes.
-pub fn target() -> String {
-    std::env::var("TARGET").unwrap()
+/// If `HUBRIS_TRACK_ENV_LOG` is set to a path, this also appends `KEY=value`
+/// (or just `KEY`, if the variable isn't set) to that file, so that tooling
+/// can compare logs from successive builds to see which variables really
+/// changed.
+fn read_tracked(key: &str) -> Result<String, std::env::VarError> {
+    let value = std::env::var(key);
+    // This is debugging instrumentation, so it isn't itself tracked
+    let log = std::env::var_os("HUBRIS_TRACK_ENV_LOG");
+    record_tracked(key, &value, log.as_deref().map(std::path::Path::new));
+    value
 }
 
-/// Reads the `TARGET_OS` environment variable
+/// Marks that `key` is used and, if `log` is given, appends its `value` there
+fn record_tracked(
+    key: &str,
+    value: &Result<String, std::env::VarError>,
+    log: Option<&std::path::Path>,
+) {
+    emit(&format!("rerun-if-env-changed={}", key));
+    if let Some(log) = log {
+        let line = match value {
+            Ok(v) => format!("{}={}", key, v),
+            Err(_) => key.to_string(),
+        };
+        if let Err(e) = append_line(log, &line) {
+            emit(&format!("warning=HUBRIS_TRACK_ENV_LOG: {:#}", e));
+        }
+    }
+}
+
+/// Appends a line to a file, creating it if needed
+fn append_line(path: &std::path::Path, line: &str) -> Result<()> {
+    use std::io::Write;
+
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    writeln!(f, "{}", line)
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Marks that the build depends on each of the given environment variables,
+/// without reading them
+///
+/// Use this instead of discarding the result of `env_var` when a variable is
+/// only needed to trigger rebuilds.
+pub fn track_env_vars(keys: &[&str]) {
+    for key in keys {
+        emit(&format!("rerun-if-env-changed={}", key));
+    }
+}
+
+/// Reads the given environment variable and marks that it's used, returning
+/// `default` if the variable is not set
+///
+/// # Panics
+/// If the variable is set but does not contain valid UTF-8
+pub fn env_var_or(key: &str, default: &str) -> String {
+    match read_tracked(key) {
+        Ok(v) => v,
+        Err(std::env::VarError::NotPresent) => default.to_string(),
+        Err(e) => panic!("reading env var ${key}: {e}"),
+    }
+}
+
+/// Reads the given environment variable as a boolean and marks that it's used
+///
+/// `1`, `true`, `yes`, and `on` are true; `0`, `false`, `no`, `off`, and the
+/// empty string are false (all case-insensitive).  Returns `Ok(None)` if the
+/// variable is not set, and an error for any other value.
+pub fn env_var_bool(key: &str) -> Result<Option<bool>> {
+    let Some(value) = maybe_env_var(key)? else {
+        return Ok(None);
+    };
+    parse_bool(&value).map(Some).ok_or_else(|| {
+        anyhow!(
+            "invalid value `{value}` for ${key}: expected one of \
+             `1`, `true`, `yes`, `on`, `0`, `false`, `no`, `off`, or empty"
+        )
+    })
+}
+
+/// Interprets a string as a boolean, per `env_var_bool`
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" | "" => Some(false),
+        _ => None,
+    }
+}
+
+/// Reads the given environment variable as a comma-separated list and marks
+/// that it's used
+///
+/// Elements are trimmed, and empty elements are dropped (so `"a, b ,,c,"` is
+/// `["a", "b", "c"]`).  Returns `None` if the variable is not set.
+///
+/// # Panics
+/// If the variable is set but does not contain valid UTF-8
+pub fn env_var_list(key: &str) -> Option<Vec<String>> {
+    maybe_env_var(key)
+        .unwrap_or_else(|e| panic!("{e:#}"))
+        .map(|value| split_list(&value))
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Reads the given environment variable, parses it as a `T`, and marks that
+/// it's used
+///
+/// Returns `Ok(None)` if the variable is not set, and an error naming the
+/// variable and its value if it can't be parsed.
+pub fn env_var_parsed<T>(key: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    parse_env_option(key, maybe_env_var(key)?)
+}
+
+fn parse_env_option<T>(key: &str, value: Option<String>) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.map(|value| parse_env_value(key, &value)).transpose()
+}
+
+fn parse_env_value<T>(key: &str, value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow!("invalid value `{value}` for ${key}: {e}"))
+}
+
+/// Reads the given environment variable and marks that it's used, returning
+/// `Ok(None)` if the variable is not set
+fn maybe_env_var(key: &str) -> Result<Option<String>> {
+    match read_tracked(key) {
+        Ok(v) => Ok(Some(v)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading env var ${key}")),
+    }
+}
+
+/// Reads the `OUT_DIR` environment variable
 ///
 /// This function goes through `std::env::var` directly, rather than our own
 /// `env_var`, because Cargo should know when it changes.
-pub fn target_os() -> String {
-    std::env::var("CARGO_CFG_TARGET_OS").unwrap()
+///
+/// # Panics
+/// If `OUT_DIR` isn't set; see `try_out_dir` for a version that returns an
+/// error instead.
+pub fn out_dir() -> std::path::PathBuf {
+    try_out_dir().expect("Could not get OUT_DIR")
 }
 
-/// Checks to see whether the given feature is enabled
-pub fn has_feature(s: &str) -> bool {
-    std::env::var(format!(
-        "CARGO_FEATURE_{}",
-        s.to_uppercase().replace('-', "_")
-    ))
-    .is_ok()
+/// Reads the `OUT_DIR` environment variable, returning an error if it isn't
+/// set (e.g. because this isn't running in a build script)
+pub fn try_out_dir() -> Result<std::path::PathBuf> {
+    std::env::var_os("OUT_DIR")
+        .map(std::path::PathBuf::from)
+        .ok_or_else(|| anyhow!("OUT_DIR is not set; is this a build script?"))
 }
 
-/// Exposes the CPU's M-profile architecture version. This isn't available in
-/// rustc's standard environment.
+/// Writes a generated file into `OUT_DIR`, returning its path (e.g. for use
+/// with `include!`)
 ///
-/// This will set one of `cfg(armv6m)`, `cfg(armv7m)`, or `cfg(armv8m)`
-/// depending on the value of the `TARGET` environment variable.
-pub fn expose_m_profile() {
-    let target = crate::target();
+/// The file is only written if its contents have changed, so that an
+/// unchanged file doesn't trigger rebuilds of everything that includes it.
+pub fn write_generated(
+    name: &str,
+    contents: &str,
+) -> Result<std::path::PathBuf> {
+    check_generated_name(name)?;
+    let path = try_out_dir()?.join(name);
+    write_if_changed(&path, contents)?;
+    Ok(path)
+}
 
-    if target.starts_with("thumbv6m") {
-        println!("cargo:rustc-cfg=armv6m");
-    } else if target.starts_with("thumbv7m") || target.starts_with("thumbv7em")
-    {
-        println!("cargo:rustc-cfg=armv7m");
-    } else if target.starts_with("thumbv8m") {
-        println!("cargo:rustc-cfg=armv8m");
-    } else {
-        println!("Don't know the target {}", target);
-        std::process::exit(1);
+/// Returns the canonical path for a generated file in `OUT_DIR`, for use by
+/// generators that don't write through `write_generated`
+///
+/// # Panics
+/// If `name` isn't a plain file name (e.g. it contains a path separator), so
+/// that the path can't escape `OUT_DIR`
+pub fn generated_path(name: &str) -> std::path::PathBuf {
+    if let Err(e) = check_generated_name(name) {
+        panic!("{e}");
     }
+    out_dir().join(name)
 }
 
-/// Exposes the board type from the `HUBRIS_BOARD` envvar into
-/// `cfg(target_board="...")`.
-pub fn expose_target_board() {
-    if let Ok(board) = crate::env_var("HUBRIS_BOARD") {
-        println!("cargo:rustc-cfg=target_board=\"{}\"", board);
+/// Checks that a generated file's name is a single, normal path component
+fn check_generated_name(name: &str) -> Result<()> {
+    let mut components = std::path::Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(c)), None)
+            if c == name && !name.contains(['/', '\\']) =>
+        {
+            Ok(())
+        }
+        _ => bail!("generated file name `{}` must be a plain file name", name),
     }
 }
 
+/// Returns the canonical name for a per-task artifact, `task_<name>_<suffix>`
+/// (e.g. `task_spi_driver_sections.ld`), so that every step that produces or
+/// looks for such files agrees on it.
 ///
-/// Pulls the app-wide configuration for purposes of a build task.  This
-/// will fail if the app-wide configuration doesn't exist or can't parse.
-/// Note that -- thanks to the magic of Serde -- `T` need not (and indeed,
-/// should not) contain the entire app-wide configuration, but rather only
-/// those parts that a particular build task cares about.  (It should go
-/// without saying that `deny_unknown_fields` should *not* be set on this
-/// type -- but it may well be set within the task-specific types that
-/// this type contains.)  If the configuration field is optional, `T` should
-/// reflect that by having its member (or members) be an `Option` type.
+/// The name of the task being built is taken from `HUBRIS_TASK_NAME`, with
+/// anything other than ASCII letters, digits, and `_` (e.g. a `-`) replaced
+/// by `_`.  It's an error if the result isn't a plain file name.
+pub fn task_output_name(suffix: &str) -> Result<String> {
+    output_name(&task_name()?, suffix)
+}
+
+fn output_name(task: &str, suffix: &str) -> Result<String> {
+    let task: String = task
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let name = format!("task_{}_{}", task, suffix);
+    check_generated_name(&name)?;
+    Ok(name)
+}
+
+/// Writes a file if its contents differ, returning whether it was written
+fn write_if_changed(path: &std::path::Path, contents: &str) -> Result<bool> {
+    if std::fs::read(path).ok().as_deref() == Some(contents.as_bytes()) {
+        return Ok(false);
+    }
+    std::fs::write(path, contents)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(true)
+}
+
+/// Writes a generated file if its contents have changed, like
+/// `write_generated`, but safely even if other build scripts may write the
+/// same file at the same time; returns whether it was written.
 ///
-pub fn config<T: DeserializeOwned>() -> Result<T> {
-    toml_from_env("HUBRIS_APP_CONFIG")?.ok_or_else(|| {
-        anyhow!("app.toml missing global config section [config]")
-    })
+/// Unlike `write_generated`, this takes a full path, since it's meant for
+/// files in a directory shared between build scripts.  Writers are
+/// serialized with an advisory lock file (the path with `.lock` appended),
+/// and the new contents are written to a temporary file private to this
+/// writer and renamed into place, so readers never see a partial file.  A
+/// lock file older than `STALE_LOCK_AGE` is assumed to be left over from a
+/// crashed build and is broken; if that happens to a writer that was just
+/// slow, it notices before renaming its file and starts over.
+pub fn write_generated_locked(
+    path: &std::path::Path,
+    contents: &str,
+) -> Result<bool> {
+    write_locked(path, contents, STALE_LOCK_AGE)
 }
 
-/// Pulls the task configuration. See `config` for more details.
-pub fn task_config<T: DeserializeOwned>() -> Result<T> {
-    let task_name =
-        crate::env_var("HUBRIS_TASK_NAME").expect("missing HUBRIS_TASK_NAME");
-    task_maybe_config()?.ok_or_else(|| {
-        anyhow!(
-            "app.toml missing task config section [tasks.{}.config]",
-            task_name
-        )
-    })
+fn write_locked(
+    path: &std::path::Path,
+    contents: &str,
+    stale_age: std::time::Duration,
+) -> Result<bool> {
+    loop {
+        let lock = FileLock::acquire(&with_suffix(path, ".lock"), stale_age)?;
+        if std::fs::read(path).ok().as_deref() == Some(contents.as_bytes()) {
+            return Ok(false);
+        }
+        let tmp = with_suffix(path, &format!(".{}.tmp", lock.nonce));
+        std::fs::write(&tmp, contents)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        if !lock.is_held() {
+            // Our lock was broken as stale, and someone else may be writing
+            let _ = std::fs::remove_file(&tmp);
+            continue;
+        }
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("renaming to {}", path.display()))?;
+        return Ok(true);
+    }
 }
 
-/// Pulls the task configuration, or `None` if the configuration is not
-/// provided.
-pub fn task_maybe_config<T: DeserializeOwned>() -> Result<Option<T>> {
-    toml_from_env("HUBRIS_TASK_CONFIG")
+/// How old a lock file must be before `write_generated_locked` assumes that
+/// its holder crashed
+pub const STALE_LOCK_AGE: std::time::Duration =
+    std::time::Duration::from_secs(30);
+
+/// Appends a suffix to a path's file name, e.g. `foo.rs` to `foo.rs.lock`
+fn with_suffix(path: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    s.into()
 }
 
-/// Returns a map of task names to their IDs.
-pub fn task_ids() -> TaskIds {
-    let tasks = crate::env_var("HUBRIS_TASKS").expect("missing HUBRIS_TASKS");
-    TaskIds(
-        tasks
-            .split(',')
-            .enumerate()
-            .map(|(i, name)| (name.to_string(), i))
-            .collect(),
+/// An advisory lock, held by creating a file that contains a nonce unique to
+/// this holder, which is removed on drop
+struct FileLock {
+    path: std::path::PathBuf,
+    nonce: String,
+}
+
+impl FileLock {
+    /// Takes the lock, waiting for it if needed; a lock file older than
+    /// `stale_age` is broken
+    ///
+    /// Since any lock is broken once it's stale, waiting for twice that long
+    /// means something is wrong (e.g. the lock file can't be read), so that's
+    /// an error rather than a hang.
+    fn acquire(
+        path: &std::path::Path,
+        stale_age: std::time::Duration,
+    ) -> Result<Self> {
+        use std::io::Write;
+
+        let nonce = unique_nonce();
+        let deadline = std::time::Instant::now() + stale_age * 2;
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(mut f) => {
+                    f.write_all(nonce.as_bytes()).with_context(|| {
+                        format!("writing lock file {}", path.display())
+                    })?;
+                    return Ok(FileLock {
+                        path: path.to_owned(),
+                        nonce,
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    // Read the holder's nonce before checking the age, so
+                    // that if the lock is replaced in between, we see the
+                    // new lock's age and leave it alone
+                    let holder = std::fs::read_to_string(path);
+                    let stale = std::fs::metadata(path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|t| t.elapsed().ok())
+                        .map_or(false, |age| age > stale_age);
+                    match holder {
+                        Ok(holder) if stale => {
+                            remove_lock_if(path, &holder, &nonce);
+                        }
+                        _ if std::time::Instant::now() > deadline => bail!(
+                            "timed out after {:?} waiting for lock file {}",
+                            stale_age * 2,
+                            path.display()
+                        ),
+                        _ => std::thread::sleep(
+                            std::time::Duration::from_millis(5),
+                        ),
+                    }
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("creating lock file {}", path.display())
+                    })
+                }
+            }
+        }
+    }
+
+    /// Checks that the lock is still ours, i.e. it hasn't been broken
+    fn is_held(&self) -> bool {
+        std::fs::read_to_string(&self.path).ok().as_deref()
+            == Some(self.nonce.as_str())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        remove_lock_if(&self.path, &self.nonce, &self.nonce);
+    }
+}
+
+/// Removes a lock file if it holds `holder`'s nonce, returning whether it did
+///
+/// Checking the contents and then removing the file would be racy, since the
+/// lock could be replaced in between.  So instead, this atomically renames
+/// the lock to a name private to the caller (`nonce`), and then checks it; if
+/// it turns out to be someone else's lock, it's put back, unless yet another
+/// lock has been taken in the meantime (in which case its owner will notice
+/// that it's lost the lock before writing anything).
+fn remove_lock_if(path: &std::path::Path, holder: &str, nonce: &str) -> bool {
+    let private = with_suffix(path, &format!(".{}.broken", nonce));
+    if std::fs::rename(path, &private).is_err() {
+        return false;
+    }
+    let removed =
+        std::fs::read_to_string(&private).ok().as_deref() == Some(holder);
+    if !removed {
+        // `hard_link` fails if the path exists, so this can't clobber a lock
+        let _ = std::fs::hard_link(&private, path);
+    }
+    let _ = std::fs::remove_file(&private);
+    removed
+}
+
+/// Returns a string unique to this call among all running processes
+fn unique_nonce() -> String {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
     )
 }
 
-/// Map of task names to their IDs.
-pub struct TaskIds(BTreeMap<String, usize>);
+/// Formats generated Rust code with `rustfmt`, then writes it into `OUT_DIR`
+/// per `write_generated`
+///
+/// If `rustfmt` is not available (or fails), the code is written as-is.
+pub fn write_generated_fmt(
+    name: &str,
+    contents: &str,
+) -> Result<std::path::PathBuf> {
+    let formatted = rustfmt_str(contents);
+    write_generated(name, formatted.as_deref().unwrap_or(contents))
+}
 
-impl TaskIds {
-    /// Get the ID of a task by name.
-    pub fn get(&self, task_name: &str) -> Option<usize> {
-        self.0.get(task_name).copied()
+/// Runs a string through `rustfmt`, returning `None` on any failure
+fn rustfmt_str(contents: &str) -> Option<String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("rustfmt")
+        .args(["--edition", "2021"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(contents.as_bytes()).ok()?;
+    let out = child.wait_with_output().ok()?;
+    if out.status.success() {
+        String::from_utf8(out.stdout).ok()
+    } else {
+        None
     }
+}
 
-    /// Convert a list of task names into a list of task IDs, ordered the same.
-    pub fn names_to_ids<S>(&self, names: &[S]) -> Result<Vec<usize>>
-    where
-        S: AsRef<str>,
-    {
-        names
-            .iter()
-            .map(|name| {
-                let name = name.as_ref();
-                self.get(name)
-                    .ok_or_else(|| anyhow!("unknown task `{}`", name))
-            })
-            .collect()
+/// Passes each of the given arguments to the linker
+pub fn emit_link_args<I: IntoIterator<Item = String>>(args: I) {
+    for arg in args {
+        emit(&format!("rustc-link-arg={}", arg));
     }
+}
 
-    /// Helper function to convert a map of operation names to allowed callers
-    /// (by name) to a map of operation names to allowed callers (by task ID).
-    pub fn remap_allowed_caller_names_to_ids(
-        &self,
-        allowed_callers: &BTreeMap<String, Vec<String>>,
-    ) -> Result<BTreeMap<String, Vec<usize>>> {
-        allowed_callers
-            .iter()
-            .map(|(name, tasks)| {
-                let task_ids = self.names_to_ids(tasks)?;
-                Ok((name.clone(), task_ids))
-            })
-            .collect()
+/// Defines a linker symbol with the given value, using `--defsym`
+pub fn emit_defsym(name: &str, value: u64) {
+    emit_link_args([defsym_arg(name, value)]);
+}
+
+fn defsym_arg(name: &str, value: u64) -> String {
+    // The linker reads bare numbers as decimal, so this must have an `0x`
+    format!("--defsym={}={:#x}", name, value)
+}
+
+/// Returns an error with the given message if `cond` is false
+///
+/// This is like `assert!`, but fails the build script through its `Result`
+/// rather than with a panic.
+pub fn build_assert(cond: bool, msg: impl std::fmt::Display) -> Result<()> {
+    if cond {
+        Ok(())
+    } else {
+        bail!("{}", msg)
     }
 }
 
-/// Parse the contents of an environment variable as toml.
+/// Returns an error with the given message (and both values) if `left` and
+/// `right` are not equal
 ///
-/// Returns:
+/// This is like `assert_eq!`, but fails the build script through its `Result`
+/// rather than with a panic.
+pub fn build_assert_eq<T>(
+    left: T,
+    right: T,
+    msg: impl std::fmt::Display,
+) -> Result<()>
+where
+    T: PartialEq + std::fmt::Debug,
+{
+    if left == right {
+        Ok(())
+    } else {
+        bail!("{}: `{:?}` != `{:?}`", msg, left, right)
+    }
+}
+
+/// Collects warnings during a build script, to emit them together (with a
+/// count, so that they stand out in a big build) when it's done.
 ///
-/// - `Ok(Some(x))` if the environment variable is defined and the contents
-///   deserialized correctly.
-/// - `Ok(None)` if the environment variable is not defined.
-/// - `Err(e)` if deserialization failed or the environment variable did not
-///   contain UTF-8.
-fn toml_from_env<T: DeserializeOwned>(var: &str) -> Result<Option<T>> {
-    let config = match crate::env_var(var) {
-        Err(e) => {
-            use std::env::VarError;
+/// ```no_run
+/// let mut warnings = build_util::WarningCollector::new();
+/// warnings.push("no I2C devices configured");
+/// warnings.finish().unwrap();
+/// ```
+#[derive(Default)]
+pub struct WarningCollector {
+    warnings: Vec<String>,
+    report: bool,
+}
 
-            return if e.downcast_ref::<std::env::VarError>()
-                == Some(&VarError::NotPresent)
-            {
-                Ok(None)
-            } else {
-                Err(e).context("reading TOML from build environment")
+impl WarningCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also writes the warnings to `OUT_DIR/warnings.json` on `finish`, as a
+    /// JSON object mapping the task name (from `HUBRIS_TASK_NAME`, or the
+    /// package name outside of a task) to a list of warnings, so that xtask
+    /// can gather them from every task.
+    pub fn with_report(mut self) -> Self {
+        self.report = true;
+        self
+    }
+
+    /// Adds a warning.
+    pub fn push(&mut self, warning: impl std::fmt::Display) {
+        self.warnings.push(warning.to_string());
+    }
+
+    /// Emits the collected warnings (if any), and writes the report if
+    /// requested.
+    pub fn finish(self) -> Result<()> {
+        for line in warning_summary(&self.warnings) {
+            emit(&format!("warning={}", line));
+        }
+        if self.report {
+            let name = match maybe_env_var("HUBRIS_TASK_NAME")? {
+                Some(task) => task,
+                None => std::env::var("CARGO_PKG_NAME")
+                    .context("reading env var $CARGO_PKG_NAME")?,
             };
+            write_generated(
+                "warnings.json",
+                &warnings_json(&name, &self.warnings)?,
+            )?;
         }
-        Ok(c) => c,
-    };
+        Ok(())
+    }
+}
 
-    println!("--- toml for ${} ---", var);
-    println!("{}", config);
-    let rval = toml::from_slice(config.as_bytes())
-        .context("deserializing configuration")?;
-    Ok(Some(rval))
+fn warning_summary(warnings: &[String]) -> Vec<String> {
+    if warnings.is_empty() {
+        return vec![];
+    }
+    let plural = if warnings.len() == 1 { "" } else { "s" };
+    std::iter::once(format!("{} build warning{}:", warnings.len(), plural))
+        .chain(warnings.iter().map(|w| format!("  - {}", w)))
+        .collect()
+}
+
+fn warnings_json(name: &str, warnings: &[String]) -> Result<String> {
+    let mut report = BTreeMap::new();
+    report.insert(name, warnings);
+    let mut json = serde_json::to_string_pretty(&report)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// Reports how long part of a build script takes, when `HUBRIS_BUILD_TIMING=1`
+/// is set.
+///
+/// The timer starts when it's created and reports (as a `cargo:warning`, so
+/// that it's visible in normal build output) when it's dropped:
+///
+/// ```no_run
+/// let _t = build_util::BuildTimer::new("i2c config parse");
+/// ```
+///
+/// Otherwise, it does nothing beyond making the build depend on
+/// `HUBRIS_BUILD_TIMING`.
+pub struct BuildTimer {
+    running: Option<(String, std::time::Instant)>,
+}
+
+impl BuildTimer {
+    /// Starts a timer with the given label, if timing is enabled.
+    pub fn new(label: &str) -> Self {
+        let enabled = maybe_env_var("HUBRIS_BUILD_TIMING")
+            .map_or(false, |v| v.as_deref() == Some("1"));
+        Self::start(label, enabled)
+    }
+
+    fn start(label: &str, enabled: bool) -> Self {
+        let running =
+            enabled.then(|| (label.to_string(), std::time::Instant::now()));
+        BuildTimer { running }
+    }
+}
+
+impl Drop for BuildTimer {
+    fn drop(&mut self) {
+        if let Some((label, start)) = &self.running {
+            emit(&format!("warning={} took {:.2?}", label, start.elapsed()));
+        }
+    }
+}
+
+/// Reads the `TARGET` environment variable
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when `OUT_DIR` changes.
+pub fn target() -> String {
+    std::env::var("TARGET").unwrap()
+}
+
+/// Reads the `TARGET_OS` environment variable
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when it changes.
+pub fn target_os() -> String {
+    std::env::var("CARGO_CFG_TARGET_OS").unwrap()
+}
+
+/// Reads the `CARGO_CFG_TARGET_ARCH` environment variable (e.g. `arm`)
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when it changes.
+pub fn target_arch() -> String {
+    std::env::var("CARGO_CFG_TARGET_ARCH").unwrap()
+}
+
+/// Reads the `CARGO_CFG_TARGET_VENDOR` environment variable (e.g. `unknown`)
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when it changes.
+pub fn target_vendor() -> String {
+    std::env::var("CARGO_CFG_TARGET_VENDOR").unwrap()
+}
+
+/// Reads the target's pointer width (in bits) from the
+/// `CARGO_CFG_TARGET_POINTER_WIDTH` environment variable
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when it changes.
+pub fn target_pointer_width() -> Result<u32> {
+    let width = std::env::var("CARGO_CFG_TARGET_POINTER_WIDTH")
+        .context("reading env var $CARGO_CFG_TARGET_POINTER_WIDTH")?;
+    match width.as_str() {
+        "32" => Ok(32),
+        "64" => Ok(64),
+        _ => bail!("unexpected target pointer width `{}`", width),
+    }
+}
+
+/// A target triple, split into its components
+///
+/// Triples have either three parts (`thumbv7em-none-eabihf`, which has no
+/// vendor, or `x86_64-apple-darwin`, which has no ABI) or four
+/// (`riscv32imac-unknown-none-elf`).  A missing vendor is `unknown`, as in
+/// `CARGO_CFG_TARGET_VENDOR`.
+///
+/// The two kinds of three-part triple can't be told apart by shape alone, so
+/// a three-part triple is taken to have a vendor only if its second part is
+/// one of a few known vendors (`apple`, `pc`, or `unknown`); otherwise, its
+/// parts are the architecture, OS, and ABI.  A three-part triple with any
+/// other vendor would be misread as having an ABI, so new vendors must be
+/// added to `TARGET_VENDORS`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TargetTriple {
+    /// Architecture, e.g. `thumbv8m.main` or `riscv32imac`
+    pub arch: String,
+    /// Vendor, e.g. `apple`, or `unknown` if the triple has none (as with
+    /// `thumbv7em-none-eabihf`)
+    pub vendor: String,
+    /// Operating system, e.g. `none` for bare metal, or `darwin`
+    pub os: String,
+    /// ABI, e.g. `eabi` or `eabihf`
+    pub abi: Option<String>,
+}
+
+/// Vendors that are recognized in three-part triples, to tell them apart
+/// from triples without a vendor
+const TARGET_VENDORS: &[&str] = &["apple", "pc", "unknown"];
+
+impl TargetTriple {
+    /// Checks whether this target uses a hard-float ABI (e.g. `eabihf`)
+    pub fn is_hard_float(&self) -> bool {
+        self.abi.as_deref().map_or(false, |abi| abi.ends_with("hf"))
+    }
+}
+
+impl std::str::FromStr for TargetTriple {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('-').collect();
+        if parts.iter().any(|p| p.is_empty()) {
+            bail!("malformed target triple `{}`", s);
+        }
+        let (arch, vendor, os, abi) = match parts[..] {
+            [arch, vendor, os] if TARGET_VENDORS.contains(&vendor) => {
+                (arch, vendor, os, None)
+            }
+            [arch, os, abi] => (arch, "unknown", os, Some(abi)),
+            [arch, vendor, os, abi] => (arch, vendor, os, Some(abi)),
+            _ => bail!("target triple `{}` must have 3 or 4 parts", s),
+        };
+        Ok(TargetTriple {
+            arch: arch.to_string(),
+            vendor: vendor.to_string(),
+            os: os.to_string(),
+            abi: abi.map(str::to_string),
+        })
+    }
+}
+
+/// Parses the `TARGET` environment variable into its components.
+pub fn parse_target() -> Result<TargetTriple> {
+    crate::target().parse()
+}
+
+/// Optimization level, as passed to rustc with `-C opt-level`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OptLevel {
+    /// Numeric levels `0` through `3`
+    Level(u8),
+    /// `s`: optimize for size
+    Size,
+    /// `z`: optimize for size, more aggressively
+    MinSize,
+}
+
+impl std::str::FromStr for OptLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "s" => Ok(Self::Size),
+            "z" => Ok(Self::MinSize),
+            "0" | "1" | "2" | "3" => Ok(Self::Level(s.parse()?)),
+            _ => bail!("unknown opt-level `{}`", s),
+        }
+    }
+}
+
+impl std::fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Level(n) => write!(f, "{}", n),
+            Self::Size => write!(f, "s"),
+            Self::MinSize => write!(f, "z"),
+        }
+    }
+}
+
+/// Reads the optimization level from Cargo's `OPT_LEVEL` environment variable
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when it changes.
+pub fn opt_level() -> Result<OptLevel> {
+    std::env::var("OPT_LEVEL")
+        .context("reading env var $OPT_LEVEL")?
+        .parse()
+}
+
+/// Exposes the optimization level into `env!("HUBRIS_OPT_LEVEL")`, spelled
+/// the same way as in Cargo profiles (`0`-`3`, `s`, or `z`).
+pub fn expose_opt_level() -> Result<()> {
+    emit(&format!("rustc-env=HUBRIS_OPT_LEVEL={}", opt_level()?));
+    Ok(())
+}
+
+/// Checks whether the target is big-endian, from the
+/// `CARGO_CFG_TARGET_ENDIAN` environment variable
+///
+/// This function goes through `std::env::var` directly, rather than our own
+/// `env_var`, because Cargo should know when it changes.
+pub fn is_big_endian() -> bool {
+    std::env::var("CARGO_CFG_TARGET_ENDIAN").unwrap() == "big"
+}
+
+/// Exposes the target's endianness, by setting `cfg(target_is_be)` on
+/// big-endian targets.
+pub fn expose_endianness() {
+    if is_big_endian() {
+        emit("rustc-cfg=target_is_be");
+    }
+}
+
+/// Checks to see whether the given feature is enabled
+pub fn has_feature(s: &str) -> bool {
+    std::env::var(feature_env_var(s)).is_ok()
+}
+
+/// Checks to see whether all of the given features are enabled
+pub fn has_all_features(names: &[&str]) -> bool {
+    all_enabled(names, has_feature)
+}
+
+/// Checks to see whether any of the given features are enabled
+pub fn has_any_feature(names: &[&str]) -> bool {
+    any_enabled(names, has_feature)
+}
+
+fn all_enabled(names: &[&str], enabled: impl Fn(&str) -> bool) -> bool {
+    names.iter().all(|n| enabled(n))
+}
+
+fn any_enabled(names: &[&str], enabled: impl Fn(&str) -> bool) -> bool {
+    names.iter().any(|n| enabled(n))
+}
+
+/// Sets `cfg(<cfg_name>)` if the given feature is enabled
+///
+/// For example, in `build.rs`:
+///
+/// ```no_run
+/// // Lets shared code use `#[cfg(has_spi)]` instead of checking for this
+/// // board's SPI feature by name
+/// build_util::cfg_from_feature("spi1", "has_spi");
+/// ```
+pub fn cfg_from_feature(feature: &str, cfg_name: &str) {
+    if has_feature(feature) {
+        emit(&format!("rustc-cfg={}", cfg_name));
+    }
+}
+
+/// Calls `cfg_from_feature` for each `(feature, cfg_name)` pair
+pub fn cfg_from_features(pairs: &[(&str, &str)]) {
+    for (feature, cfg_name) in pairs {
+        cfg_from_feature(feature, cfg_name);
+    }
+}
+
+/// Returns the set of all enabled features
+///
+/// Cargo normalizes feature names when exposing them to build scripts (both
+/// `foo-bar` and `foo_bar` become `CARGO_FEATURE_FOO_BAR`), so dashes can't
+/// be recovered; names are returned in lowercase with underscores.
+pub fn enabled_features() -> std::collections::BTreeSet<String> {
+    features_from_vars(std::env::vars().map(|(k, _)| k))
+}
+
+fn features_from_vars(
+    vars: impl Iterator<Item = String>,
+) -> std::collections::BTreeSet<String> {
+    vars.filter_map(|k| k.strip_prefix("CARGO_FEATURE_").map(str::to_lowercase))
+        .collect()
+}
+
+/// Returns the name of the environment variable that Cargo sets when the
+/// given feature is enabled
+fn feature_env_var(s: &str) -> String {
+    format!("CARGO_FEATURE_{}", s.to_uppercase().replace('-', "_"))
+}
+
+/// Returns an error if the given feature is not enabled
+pub fn require_feature(name: &str) -> Result<()> {
+    if has_feature(name) {
+        Ok(())
+    } else {
+        bail!("feature `{}` must be enabled for this task", name)
+    }
+}
+
+/// Returns the one enabled feature from a set of mutually exclusive
+/// features, or an error if zero or more than one of them is enabled
+pub fn require_one_of<'a>(names: &[&'a str]) -> Result<&'a str> {
+    one_enabled(names, has_feature)
+}
+
+/// Returns the single name from `names` for which `enabled` is true
+fn one_enabled<'a>(
+    names: &[&'a str],
+    enabled: impl Fn(&str) -> bool,
+) -> Result<&'a str> {
+    let on: Vec<&str> = names.iter().copied().filter(|n| enabled(n)).collect();
+    match on.as_slice() {
+        [one] => Ok(one),
+        [] => bail!(
+            "exactly one of features {} must be enabled",
+            quoted_list(names)
+        ),
+        _ => bail!(
+            "only one of features {} may be enabled, but found {}",
+            quoted_list(names),
+            quoted_list(&on),
+        ),
+    }
+}
+
+/// Formats a list of names as "`a`, `b`, `c`"
+fn quoted_list<S: AsRef<str>>(names: &[S]) -> String {
+    names
+        .iter()
+        .map(|n| format!("`{}`", n.as_ref()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// M-profile architecture version of the target CPU
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MProfile {
+    V6M,
+    V7M,
+    /// ARMv8-M Baseline, e.g. Cortex-M23
+    V8MBase,
+    /// ARMv8-M Mainline, e.g. Cortex-M33
+    V8MMain,
+}
+
+impl MProfile {
+    /// Parses the architecture version out of a target triple
+    fn from_target(target: &str) -> Result<Self> {
+        if target.starts_with("thumbv6m") {
+            Ok(Self::V6M)
+        } else if target.starts_with("thumbv7m")
+            || target.starts_with("thumbv7em")
+        {
+            Ok(Self::V7M)
+        } else if target.starts_with("thumbv8m.base") {
+            Ok(Self::V8MBase)
+        } else if target.starts_with("thumbv8m.main") {
+            Ok(Self::V8MMain)
+        } else {
+            Err(anyhow!("Don't know the target {}", target))
+        }
+    }
+
+    /// Returns the name of the architecture version, which is also the name of
+    /// its `cfg` flag.  Both flavors of ARMv8-M are `armv8m`.
+    fn arch(&self) -> &'static str {
+        match self {
+            Self::V6M => "armv6m",
+            Self::V7M => "armv7m",
+            Self::V8MBase | Self::V8MMain => "armv8m",
+        }
+    }
+
+    /// Returns the names of the `cfg` flags for this architecture version:
+    /// `arch`, plus `armv8m_base` or `armv8m_main` for ARMv8-M.
+    fn cfgs(&self) -> &'static [&'static str] {
+        match self {
+            Self::V6M => &["armv6m"],
+            Self::V7M => &["armv7m"],
+            Self::V8MBase => &["armv8m", "armv8m_base"],
+            Self::V8MMain => &["armv8m", "armv8m_main"],
+        }
+    }
+}
+
+/// Returns the CPU's M-profile architecture version, based on the value of
+/// the `TARGET` environment variable.
+pub fn m_profile() -> Result<MProfile> {
+    MProfile::from_target(&crate::target())
+}
+
+/// Exposes the CPU's M-profile architecture version. This isn't available in
+/// rustc's standard environment.
+///
+/// This will set one of `cfg(armv6m)`, `cfg(armv7m)`, or `cfg(armv8m)`
+/// depending on the value of the `TARGET` environment variable.  ARMv8-M
+/// targets additionally get `cfg(armv8m_base)` (`thumbv8m.base`, e.g.
+/// Cortex-M23) or `cfg(armv8m_main)` (`thumbv8m.main`, e.g. Cortex-M33).
+pub fn expose_m_profile() {
+    match m_profile() {
+        Ok(profile) => {
+            for cfg in profile.cfgs() {
+                emit(&format!("rustc-cfg={}", cfg));
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Checks that no enabled feature is restricted to other architectures.
+///
+/// Each rule is a feature name and the architectures it may be used on, which
+/// are matched against the `cfg` flags from `expose_m_profile` (so `armv8m`
+/// allows both flavors of ARMv8-M, while `armv8m_main` allows only one).  If a
+/// feature is enabled on any other architecture, this returns an error.
+pub fn assert_feature_arch_compat(rules: &[(&str, &[&str])]) -> Result<()> {
+    check_feature_arch(rules, m_profile()?, has_feature)
+}
+
+fn check_feature_arch(
+    rules: &[(&str, &[&str])],
+    profile: MProfile,
+    enabled: impl Fn(&str) -> bool,
+) -> Result<()> {
+    let cfgs = profile.cfgs();
+    for (feature, allowed) in rules {
+        if enabled(feature) && !allowed.iter().any(|a| cfgs.contains(a)) {
+            bail!(
+                "feature `{}` is only supported on {}, not {}",
+                feature,
+                quoted_list(allowed),
+                profile.arch()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Exposes the CPU's instruction set architecture, for builds that may target
+/// either ARM or RISC-V.
+///
+/// For thumb targets, this sets the same `cfg(armv*m)` flags as
+/// `expose_m_profile` (including `armv8m_base` or `armv8m_main`).  For
+/// `riscv32*` targets (e.g. `riscv32imac` or `riscv32imc`), this sets
+/// `cfg(riscv32)`.  In both cases, it also sets
+/// `cfg(isa="...")` with the architecture name.
+pub fn expose_isa() -> Result<()> {
+    for cfg in isa_cfgs(&crate::target())? {
+        emit(&format!("rustc-cfg={}", cfg));
+    }
+    Ok(())
+}
+
+/// Returns the `cfg` flags describing the ISA of the given target triple
+fn isa_cfgs(target: &str) -> Result<Vec<String>> {
+    if let Ok(profile) = MProfile::from_target(target) {
+        let mut cfgs: Vec<String> =
+            profile.cfgs().iter().map(|c| c.to_string()).collect();
+        cfgs.push(format!("isa=\"{}\"", profile.arch()));
+        return Ok(cfgs);
+    }
+    match target.split('-').next() {
+        Some(arch) if arch.starts_with("riscv32") => {
+            Ok(vec!["riscv32".to_string(), format!("isa=\"{}\"", arch)])
+        }
+        _ => Err(anyhow!("don't know the ISA for target `{}`", target)),
+    }
+}
+
+/// Exposes whether the CPU has a hardware FPU, based on the ABI in the
+/// `TARGET` triple.
+///
+/// This will set `cfg(has_fpu)` for hard-float (`eabihf`) targets, e.g.
+/// `thumbv7em-none-eabihf` or `thumbv8m.main-none-eabihf`.
+pub fn expose_fpu() {
+    if target_has_fpu(&crate::target()) {
+        emit("rustc-cfg=has_fpu");
+    }
+}
+
+/// Checks whether a target triple selects the hard-float ABI
+fn target_has_fpu(target: &str) -> bool {
+    target
+        .parse::<TargetTriple>()
+        .map_or(false, |t| t.is_hard_float())
+}
+
+/// Exposes the git commit being built into `env!("HUBRIS_GIT_VERSION")`
+///
+/// This is the short commit hash, with a `-dirty` suffix if the working tree
+/// has uncommitted changes.  If `git` isn't available (or we're not building
+/// from a git checkout), it's `unknown` instead of a build failure.
+pub fn expose_git_version() {
+    track_git_head();
+    let version = git_version().unwrap_or_else(|| "unknown".to_string());
+    emit(&format!("rustc-env=HUBRIS_GIT_VERSION={}", version));
+}
+
+/// Tracks the files that change when a new commit is made (or a different
+/// branch is checked out), so that `git_version` is rerun
+fn track_git_head() {
+    // `HEAD` changes when switching branches; the branch ref that it points
+    // to changes on commit (or `packed-refs`, if the ref has been packed).
+    // In a worktree, `HEAD` is per-worktree but refs are shared, so each path
+    // is resolved by `git rev-parse --git-path` rather than joined onto
+    // `--git-dir`.  We only track files that exist, because Cargo always
+    // reruns the build script if a `rerun-if-changed` file is missing, and
+    // skip any path that `git` can't resolve.
+    let mut tracked = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        tracked.push(head_ref);
+    }
+    for path in &tracked {
+        let Some(path) = git(&["rev-parse", "--git-path", path]) else {
+            continue;
+        };
+        if std::path::Path::new(&path).exists() {
+            emit(&format!("rerun-if-changed={}", path));
+        }
+    }
+}
+
+/// Returns the short commit hash (plus `-dirty`), or `None` if `git` isn't
+/// usable
+fn git_version() -> Option<String> {
+    let hash = git(&["rev-parse", "--short", "HEAD"])?;
+    let dirty = !git(&["status", "--porcelain"])?.is_empty();
+    Some(if dirty {
+        format!("{}-dirty", hash)
+    } else {
+        hash
+    })
+}
+
+/// Runs `git` with the given arguments, returning its trimmed output if it
+/// succeeded
+fn git(args: &[&str]) -> Option<String> {
+    let out = std::process::Command::new("git").args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(out.stdout).ok()?.trim().to_string())
+}
+
+/// Exposes whether the CPU has the DSP extension, by setting `cfg(has_dsp)`.
+///
+/// This distinguishes `thumbv7em` targets (Cortex-M4/M7, which have it) from
+/// `thumbv7m` (Cortex-M3, which doesn't); both are still `cfg(armv7m)`.
+pub fn expose_dsp() {
+    if target_has_dsp(&crate::target()) {
+        emit("rustc-cfg=has_dsp");
+    }
+}
+
+fn target_has_dsp(target: &str) -> bool {
+    target.starts_with("thumbv7em")
+}
+
+/// Exposes whether TrustZone (the ARMv8-M security extension) is in use, by
+/// setting `cfg(has_trustzone)`.
+///
+/// TrustZone can be fused off (or simply unused) even on parts that support
+/// it, so this is only set for ARMv8-M targets when the app also opts in with
+/// `secure-separation = true`, which xtask passes along as `HUBRIS_SECURE=0`
+/// (i.e. Hubris itself runs non-secure).
+pub fn expose_security_extension() {
+    let secure = crate::env_var("HUBRIS_SECURE").ok();
+    if has_trustzone(&crate::target(), secure.as_deref()) {
+        emit("rustc-cfg=has_trustzone");
+    }
+}
+
+fn has_trustzone(target: &str, hubris_secure: Option<&str>) -> bool {
+    matches!(
+        MProfile::from_target(target),
+        Ok(MProfile::V8MBase | MProfile::V8MMain)
+    ) && hubris_secure == Some("0")
+}
+
+/// Number of MPU regions, by architecture and (optionally) board.  Entries with
+/// a board take precedence over the architecture-wide default.
+const MPU_REGIONS: &[(MProfile, Option<&str>, u32)] = &[
+    (MProfile::V6M, None, 8),
+    (MProfile::V7M, None, 8),
+    (MProfile::V8MBase, None, 8),
+    (MProfile::V8MMain, None, 8),
+    // The Cortex-M7 on STM32H7 parts has 16 regions
+    (MProfile::V7M, Some("gemini-bu-1"), 16),
+    (MProfile::V7M, Some("gimlet-b"), 16),
+    (MProfile::V7M, Some("gimlet-c"), 16),
+    (MProfile::V7M, Some("gimletlet-1"), 16),
+    (MProfile::V7M, Some("gimletlet-2"), 16),
+    (MProfile::V7M, Some("nucleo-h743zi2"), 16),
+    (MProfile::V7M, Some("nucleo-h753zi"), 16),
+    (MProfile::V7M, Some("psc-a"), 16),
+    (MProfile::V7M, Some("psc-b"), 16),
+    (MProfile::V7M, Some("sidecar-a"), 16),
+    (MProfile::V7M, Some("sidecar-b"), 16),
+];
+
+/// Returns the number of MPU regions supported by the target.
+///
+/// This is looked up by architecture and board, but can be overridden by
+/// `mpu_regions` in the global `[config]` table.
+pub fn mpu_region_count() -> Result<u32> {
+    let over = config_override("mpu_regions")?;
+    let board = maybe_env_var("HUBRIS_BOARD")?;
+    mpu_regions_for(m_profile()?, board.as_deref(), over)
+}
+
+/// Exposes `mpu_region_count` into `env!("HUBRIS_MPU_REGIONS")`.
+pub fn expose_mpu_region_count() -> Result<()> {
+    emit(&format!(
+        "rustc-env=HUBRIS_MPU_REGIONS={}",
+        mpu_region_count()?
+    ));
+    Ok(())
+}
+
+fn mpu_regions_for(
+    profile: MProfile,
+    board: Option<&str>,
+    over: Option<u32>,
+) -> Result<u32> {
+    if let Some(n) = over {
+        return Ok(n);
+    }
+    let lookup = |board| {
+        MPU_REGIONS
+            .iter()
+            .find(|(p, b, _)| *p == profile && *b == board)
+            .map(|(_, _, n)| *n)
+    };
+    board
+        .and_then(|b| lookup(Some(b)))
+        .or_else(|| lookup(None))
+        .ok_or_else(|| {
+            anyhow!(
+                "no MPU region count known for {:?}; add it to MPU_REGIONS in \
+                 build/util/src/lib.rs",
+                profile
+            )
+        })
+}
+
+/// Exposes the board type from the `HUBRIS_BOARD` envvar into
+/// `cfg(target_board="...")`.
+pub fn expose_target_board() {
+    if let Ok(board) = board() {
+        emit(&format!("rustc-cfg=target_board=\"{}\"", board));
+    }
+}
+
+/// Returns the board name from the `HUBRIS_BOARD` envvar.
+pub fn board() -> Result<String> {
+    crate::env_var("HUBRIS_BOARD")
+        .context("board name is not set; is this being built by xtask?")
+}
+
+/// Checks whether we're building for the given board.
+pub fn board_is(name: &str) -> bool {
+    board().map_or(false, |b| b == name)
+}
+
+/// Chip family of each board, as exposed by `expose_chip_family`.
+const CHIP_FAMILIES: &[(&str, &str)] = &[
+    ("donglet-g030", "g0"),
+    ("donglet-g031", "g0"),
+    ("gemini-bu-1", "h7"),
+    ("gimlet-b", "h7"),
+    ("gimlet-c", "h7"),
+    ("gimlet-rot-b", "lpc55"),
+    ("gimlet-rot-c", "lpc55"),
+    ("gimletlet-1", "h7"),
+    ("gimletlet-2", "h7"),
+    ("lpcxpresso55s69", "lpc55"),
+    ("nucleo-h743zi2", "h7"),
+    ("nucleo-h753zi", "h7"),
+    ("psc-a", "h7"),
+    ("psc-b", "h7"),
+    ("rot-carrier-1", "lpc55"),
+    ("rot-carrier-2", "lpc55"),
+    ("sidecar-a", "h7"),
+    ("sidecar-b", "h7"),
+    ("stm32f3-discovery", "f3"),
+    ("stm32f4-discovery", "f4"),
+    ("stm32g031-nucleo", "g0"),
+    ("stm32g070", "g0"),
+];
+
+/// Returns the chip family (e.g. `h7` or `lpc55`) of the board being built
+/// for, from `HUBRIS_BOARD`.
+pub fn chip_family() -> Result<String> {
+    chip_family_for(&board()?).map(str::to_string)
+}
+
+/// Exposes the chip family into `cfg(chip_family="...")`.
+pub fn expose_chip_family() -> Result<()> {
+    emit(&format!("rustc-cfg=chip_family=\"{}\"", chip_family()?));
+    Ok(())
+}
+
+fn chip_family_for(board: &str) -> Result<&'static str> {
+    CHIP_FAMILIES
+        .iter()
+        .find(|(b, _)| *b == board)
+        .map(|(_, family)| *family)
+        .ok_or_else(|| {
+            anyhow!(
+                "no chip family known for board `{}`; add it to \
+                 CHIP_FAMILIES in build/util/src/lib.rs",
+                board
+            )
+        })
+}
+
+/// Number of NVIC priority bits implemented, by chip family (see
+/// `__NVIC_PRIO_BITS` in each vendor's headers)
+const NVIC_PRIORITY_BITS: &[(&str, u8)] = &[
+    ("f3", 4),
+    ("f4", 4),
+    // ARMv6-M parts always have 2
+    ("g0", 2),
+    ("h7", 4),
+    ("lpc55", 3),
+];
+
+/// Returns the number of interrupt priority bits implemented by the NVIC.
+///
+/// This is looked up by chip family, but can be overridden by
+/// `nvic_priority_bits` in the global `[config]` table (which must be between
+/// 1 and 8).
+pub fn nvic_priority_bits() -> Result<u8> {
+    match config_override("nvic_priority_bits")? {
+        Some(bits) => check_priority_bits(bits),
+        None => family_priority_bits(&chip_family()?),
+    }
+}
+
+/// Exposes `nvic_priority_bits` into `env!("HUBRIS_NVIC_PRIO_BITS")`.
+pub fn expose_nvic_priority_bits() -> Result<()> {
+    emit(&format!(
+        "rustc-env=HUBRIS_NVIC_PRIO_BITS={}",
+        nvic_priority_bits()?
+    ));
+    Ok(())
+}
+
+fn check_priority_bits(bits: u8) -> Result<u8> {
+    if !(1..=8).contains(&bits) {
+        bail!("nvic_priority_bits must be between 1 and 8, not {}", bits);
+    }
+    Ok(bits)
+}
+
+fn family_priority_bits(family: &str) -> Result<u8> {
+    NVIC_PRIORITY_BITS
+        .iter()
+        .find(|(f, _)| *f == family)
+        .map(|(_, bits)| *bits)
+        .ok_or_else(|| {
+            anyhow!(
+                "no NVIC priority bits known for chip family `{}`; add it to \
+                 NVIC_PRIORITY_BITS in build/util/src/lib.rs",
+                family
+            )
+        })
+}
+
+/// Maximum system clock frequency of each chip family, in Hz
+const MAX_SYSCLK_HZ: &[(&str, u32)] = &[
+    ("f3", 72_000_000),
+    ("f4", 180_000_000),
+    ("g0", 64_000_000),
+    ("h7", 480_000_000),
+    ("lpc55", 150_000_000),
+];
+
+/// Returns the system clock frequency in Hz, from `sysclk_hz` in the global
+/// `[config.clocks]` table.
+///
+/// This is checked against the maximum frequency of the chip family (see
+/// `chip_family`), and must be at least 1 MHz.
+pub fn sysclk_hz() -> Result<u32> {
+    let hz: Option<u32> = config_get("clocks.sysclk_hz")?;
+    let hz = hz.ok_or_else(|| {
+        anyhow!("app.toml has no `sysclk_hz` in [config.clocks]")
+    })?;
+    check_sysclk(&chip_family()?, hz)
+}
+
+/// Exposes `sysclk_hz` into `env!("HUBRIS_SYSCLK_HZ")`.
+pub fn expose_sysclk() -> Result<()> {
+    emit(&format!("rustc-env=HUBRIS_SYSCLK_HZ={}", sysclk_hz()?));
+    Ok(())
+}
+
+fn check_sysclk(family: &str, hz: u32) -> Result<u32> {
+    const MIN_SYSCLK_HZ: u32 = 1_000_000;
+
+    let max = MAX_SYSCLK_HZ
+        .iter()
+        .find(|(f, _)| *f == family)
+        .map(|(_, max)| *max)
+        .ok_or_else(|| {
+            anyhow!(
+                "no maximum clock frequency known for chip family `{}`; add \
+                 it to MAX_SYSCLK_HZ in build/util/src/lib.rs",
+                family
+            )
+        })?;
+    if !(MIN_SYSCLK_HZ..=max).contains(&hz) {
+        bail!(
+            "sysclk_hz = {} is out of range for chip family `{}` \
+             ({}..={} Hz)",
+            hz,
+            family,
+            MIN_SYSCLK_HZ,
+            max
+        );
+    }
+    Ok(hz)
+}
+
+/// How far (in percent) the rate achieved by `clock_divisor` may be from the
+/// requested rate, which is within what UARTs can tolerate
+pub const CLOCK_DIVISOR_TOLERANCE_PERCENT: f64 = 2.0;
+
+/// A clock divisor chosen by `clock_divisor`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClockDivisor {
+    pub divisor: u32,
+    /// The rate that the divisor actually gives, rounded down
+    pub achieved_hz: u32,
+    /// How far the achieved rate is from the requested one, as a signed
+    /// percentage (negative if it's slower)
+    pub error_percent: f64,
+}
+
+/// Returns the integer divisor of `sysclk_hz` nearest to `target_hz` (e.g. a
+/// baud rate), with the rate that it achieves.
+///
+/// It's an error if the achieved rate is more than
+/// `CLOCK_DIVISOR_TOLERANCE_PERCENT` from `target_hz`.
+pub fn clock_divisor(target_hz: u32) -> Result<ClockDivisor> {
+    nearest_divisor(sysclk_hz()?, target_hz)
+}
+
+fn nearest_divisor(clock_hz: u32, target_hz: u32) -> Result<ClockDivisor> {
+    if target_hz == 0 {
+        bail!("can't divide the clock down to 0 Hz");
+    }
+    let (clock, target) = (u64::from(clock_hz), u64::from(target_hz));
+    // Round to the nearest divisor, rather than truncating
+    let divisor = ((clock + target / 2) / target).max(1);
+    let achieved = clock as f64 / divisor as f64;
+    let error_percent = (achieved - target as f64) / target as f64 * 100.0;
+    if error_percent.abs() > CLOCK_DIVISOR_TOLERANCE_PERCENT {
+        bail!(
+            "can't get {} Hz from a {} Hz clock: the nearest divisor, {}, \
+             gives {:.0} Hz ({:+.2}% off)",
+            target_hz,
+            clock_hz,
+            divisor,
+            achieved,
+            error_percent
+        );
+    }
+    Ok(ClockDivisor {
+        // This is at most `clock_hz`, so it fits
+        divisor: divisor as u32,
+        achieved_hz: (clock / divisor) as u32,
+        error_percent,
+    })
+}
+
+/// Kind of image being built, as passed by xtask in `HUBRIS_IMAGE_KIND`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImageKind {
+    Kernel,
+    Task,
+    Bootloader,
+}
+
+impl ImageKind {
+    const ALL: [Self; 3] = [Self::Kernel, Self::Task, Self::Bootloader];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Kernel => "kernel",
+            Self::Task => "task",
+            Self::Bootloader => "bootloader",
+        }
+    }
+}
+
+impl std::str::FromStr for ImageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> =
+                    Self::ALL.iter().map(Self::name).collect();
+                anyhow!(
+                    "unknown image kind `{}` (expected one of {})",
+                    s,
+                    quoted_list(&names)
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Returns the kind of image being built, from `HUBRIS_IMAGE_KIND`.
+///
+/// xtask sets this to `kernel` or `task`; a bootloader built outside of xtask
+/// must set it to `bootloader` itself.
+pub fn image_kind() -> Result<ImageKind> {
+    crate::env_var("HUBRIS_IMAGE_KIND")?
+        .parse()
+        .context("parsing $HUBRIS_IMAGE_KIND")
+}
+
+/// Exposes the kind of image being built into `cfg(image_kind="...")`, e.g.
+/// `cfg(image_kind="bootloader")`.
+pub fn expose_image_kind() -> Result<()> {
+    emit(&format!("rustc-cfg=image_kind=\"{}\"", image_kind()?));
+    Ok(())
+}
+
+/// Wire format for a task's IPC serialization, as selected by `serialization`
+/// in its `[tasks.X.config]`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SerFormat {
+    /// Smallest encoding, for production images (the default)
+    #[default]
+    Compact,
+    /// Self-describing encoding, which is larger but easier to inspect
+    Debuggable,
+}
+
+impl SerFormat {
+    const ALL: [Self; 2] = [Self::Compact, Self::Debuggable];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Compact => "compact",
+            Self::Debuggable => "debuggable",
+        }
+    }
+}
+
+impl std::str::FromStr for SerFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> =
+                    Self::ALL.iter().map(Self::name).collect();
+                anyhow!(
+                    "unknown serialization format `{}` (expected one of {})",
+                    s,
+                    quoted_list(&names)
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for SerFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Returns the serialization format selected by `serialization` in the task
+/// configuration, or `SerFormat::Compact` if it's not set (or there's no task
+/// configuration).
+pub fn task_serialization_format() -> Result<SerFormat> {
+    serialization_format(task_config_value()?.as_ref())
+}
+
+fn serialization_format(config: Option<&toml::Value>) -> Result<SerFormat> {
+    match config.and_then(|c| c.get("serialization")) {
+        None => Ok(SerFormat::default()),
+        Some(toml::Value::String(s)) => s.parse(),
+        Some(v) => bail!("`serialization` must be a string, not {}", v),
+    }
+}
+
+/// Exposes the task's serialization format into `cfg(serialization="...")`,
+/// e.g. `cfg(serialization="compact")`, for selecting a codec.
+pub fn expose_task_serialization() -> Result<()> {
+    let format = task_serialization_format()?;
+    emit(&format!("rustc-cfg=serialization=\"{}\"", format));
+    Ok(())
+}
+
+/// Exposes the given top-level fields of the app-wide configuration as `cfg`
+/// flags, so that code can be conditionally compiled on them.
+///
+/// A boolean field sets a bare `cfg(key)` when it's true (and nothing when
+/// it's false); a string field sets `cfg(key="value")`.  Dashes in keys
+/// become underscores.  Keys that aren't in the configuration are skipped,
+/// and any other kind of value is an error.
+pub fn cfg_from_config(keys: &[&str]) -> Result<()> {
+    let config: toml::Value = config()?;
+    for cfg in config_cfgs(&config, keys)? {
+        emit(&format!("rustc-cfg={}", cfg));
+    }
+    Ok(())
+}
+
+fn config_cfgs(config: &toml::Value, keys: &[&str]) -> Result<Vec<String>> {
+    let mut out = vec![];
+    for &key in keys {
+        let Some(value) = config.get(key) else {
+            continue;
+        };
+        let name = key.replace('-', "_");
+        let valid = name
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            bail!("config key `{}` can't be used as a cfg name", key);
+        }
+        match value {
+            toml::Value::Boolean(true) => out.push(name),
+            toml::Value::Boolean(false) => (),
+            // Debug formatting quotes and escapes it as a Rust string literal
+            toml::Value::String(s) => out.push(format!("{}={:?}", name, s)),
+            v => bail!(
+                "config key `{}` has type {}, but only booleans and strings \
+                 can be cfgs",
+                key,
+                v.type_str()
+            ),
+        }
+    }
+    Ok(out)
+}
+
+/// Makes the build depend on the app's TOML file itself, from the path that
+/// xtask passes in `HUBRIS_APP_TOML`.
+///
+/// If `HUBRIS_APP_TOML` isn't set (e.g. with an older xtask), this does
+/// nothing.
+pub fn track_app_toml() {
+    if let Ok(path) = crate::env_var("HUBRIS_APP_TOML") {
+        emit(&format!("rerun-if-changed={}", path));
+    }
+}
+
+///
+/// Pulls the app-wide configuration for purposes of a build task.  This
+/// will fail if the app-wide configuration doesn't exist or can't parse.
+/// Note that -- thanks to the magic of Serde -- `T` need not (and indeed,
+/// should not) contain the entire app-wide configuration, but rather only
+/// those parts that a particular build task cares about.  (It should go
+/// without saying that `deny_unknown_fields` should *not* be set on this
+/// type -- but it may well be set within the task-specific types that
+/// this type contains.)  If the configuration field is optional, `T` should
+/// reflect that by having its member (or members) be an `Option` type.
+///
+pub fn config<T: DeserializeOwned>() -> Result<T> {
+    toml_from_env("HUBRIS_APP_CONFIG")?.ok_or_else(|| {
+        anyhow!("app.toml missing global config section [config]")
+    })
+}
+
+/// Pulls the app-wide configuration, like `config`, but warns about top-level
+/// sections that aren't in `known`.
+///
+/// A misspelled section (e.g. `[config.cofig]`) otherwise parses silently as
+/// if it were absent, since `T` can't use `deny_unknown_fields`.  Each unknown
+/// section produces a `cargo:warning` rather than an error, so that this can
+/// be used by build scripts that only know about some of the sections.
+pub fn config_strict_sections<T: DeserializeOwned>(
+    known: &[&str],
+) -> Result<T> {
+    let value: toml::Value = config()?;
+    for section in unknown_sections(&value, known) {
+        emit(&format!(
+            "warning=unknown section `{}` in [config] (expected one of {})",
+            section,
+            quoted_list(known)
+        ));
+    }
+    value.try_into().context("deserializing configuration")
+}
+
+/// Returns the top-level keys of `config` that aren't in `known`
+fn unknown_sections<'a>(
+    config: &'a toml::Value,
+    known: &[&str],
+) -> Vec<&'a str> {
+    config
+        .as_table()
+        .into_iter()
+        .flat_map(|t| t.keys())
+        .map(String::as_str)
+        .filter(|k| !known.contains(k))
+        .collect()
+}
+
+/// Pulls the app-wide configuration, after expanding `${NAME}` placeholders in
+/// its string values with the values of environment variables.  See `config`
+/// for more details.
+///
+/// Placeholders are only expanded inside string values, after parsing, so a
+/// variable's value is used as-is (quotes and all) and can't change the
+/// structure of the configuration; keys are left alone.  It's an error for a
+/// placeholder to name an unset variable; to write a literal `${`, use `$${`.
+pub fn config_expanded<T: DeserializeOwned>() -> Result<T> {
+    let mut config: toml::Value = config()?;
+    expand_env_values(&mut config, "", &maybe_env_var)?;
+    config
+        .try_into()
+        .context("deserializing expanded configuration")
+}
+
+/// Expands placeholders (per `expand_env`) in every string within `value`,
+/// whose dotted path (as in `config_get`) is `path`
+fn expand_env_values(
+    value: &mut toml::Value,
+    path: &str,
+    lookup: &dyn Fn(&str) -> Result<Option<String>>,
+) -> Result<()> {
+    let child = |segment: &dyn std::fmt::Display| {
+        if path.is_empty() {
+            segment.to_string()
+        } else {
+            format!("{}.{}", path, segment)
+        }
+    };
+    match value {
+        toml::Value::String(s) => {
+            *s = expand_env(s, lookup)
+                .with_context(|| format!("in config value `{}`", path))?;
+        }
+        toml::Value::Array(a) => {
+            for (i, v) in a.iter_mut().enumerate() {
+                expand_env_values(v, &child(&i), lookup)?;
+            }
+        }
+        toml::Value::Table(t) => {
+            for (k, v) in t.iter_mut() {
+                expand_env_values(v, &child(k), lookup)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Expands `${NAME}` placeholders using `lookup`, and `$${` into `${`
+fn expand_env(
+    text: &str,
+    lookup: impl Fn(&str) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${") {
+            let end = r.find('}').ok_or_else(|| {
+                anyhow!(
+                    "unterminated placeholder `${{{}`",
+                    r.lines().next().unwrap_or("")
+                )
+            })?;
+            let name = &r[..end];
+            let value = lookup(name)?.ok_or_else(|| {
+                anyhow!(
+                    "config placeholder `${{{}}}` names an unset variable",
+                    name
+                )
+            })?;
+            out.push_str(&value);
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Pulls the app-wide configuration, after pulling in shared fragments listed
+/// in a top-level `include = ["path/to/frag.toml", ...]` key.  See `config`
+/// for more details.
+///
+/// Include paths are relative to `HUBRIS_CONFIG_DIR`, and fragments may have
+/// includes of their own.  The fragments are merged in the order listed (per
+/// `merge_toml`), and then the including table is merged over them, so its own
+/// values take precedence.  An include cycle is an error.
+pub fn config_with_includes<T: DeserializeOwned>() -> Result<T> {
+    let config: toml::Value = config()?;
+    let dir = maybe_env_var("HUBRIS_CONFIG_DIR")?;
+    resolve_includes(
+        config,
+        dir.as_deref().map(std::path::Path::new),
+        &mut vec![],
+    )?
+    .try_into()
+    .context("deserializing configuration")
+}
+
+/// Replaces the `include` key of `config` with the contents of the files that
+/// it lists, recursively
+///
+/// `stack` holds the canonical path (and the path as written) of each file
+/// being included, outermost first, for detecting cycles.
+fn resolve_includes(
+    mut config: toml::Value,
+    dir: Option<&std::path::Path>,
+    stack: &mut Vec<(std::path::PathBuf, String)>,
+) -> Result<toml::Value> {
+    let includes = match config.as_table_mut().and_then(|t| t.remove("include"))
+    {
+        None => return Ok(config),
+        Some(toml::Value::Array(includes)) => includes,
+        Some(v) => bail!("`include` must be a list of paths, not {}", v),
+    };
+    let dir = dir.ok_or_else(|| {
+        anyhow!("config uses `include`, but HUBRIS_CONFIG_DIR is not set")
+    })?;
+
+    let mut merged = toml::Value::Table(Default::default());
+    for include in includes {
+        let name = include.as_str().ok_or_else(|| {
+            anyhow!("include path must be a string, not {}", include)
+        })?;
+        let path = dir.join(name);
+        emit(&format!("rerun-if-changed={}", path.display()));
+        let canonical = std::fs::canonicalize(&path)
+            .with_context(|| format!("reading include {}", path.display()))?;
+        if let Some(i) = stack.iter().position(|(p, _)| *p == canonical) {
+            let cycle: Vec<&str> = stack[i..]
+                .iter()
+                .map(|(_, n)| n.as_str())
+                .chain(std::iter::once(name))
+                .collect();
+            bail!("include cycle: {}", cycle.join(" -> "));
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading include {}", path.display()))?;
+        let fragment: toml::Value = toml::from_str(&text)
+            .with_context(|| format!("parsing include {}", path.display()))?;
+
+        stack.push((canonical, name.to_string()));
+        let fragment = resolve_includes(fragment, Some(dir), stack)?;
+        stack.pop();
+        merge_toml(&mut merged, fragment);
+    }
+    merge_toml(&mut merged, config);
+    Ok(merged)
+}
+
+/// Pulls a single value out of the app-wide configuration by its dotted path
+/// (e.g. `net.dhcp.retries`), or `None` if any part of the path is absent.
+///
+/// A path segment that's a number indexes into an array, e.g. `spi.0.bus`.
+/// It's an error if the value is present but can't be deserialized as `T`.
+pub fn config_get<T: DeserializeOwned>(dotted_path: &str) -> Result<Option<T>> {
+    let config: toml::Value = config()?;
+    lookup_path(&config, dotted_path)
+}
+
+/// Like `config_get`, but an app with no `[config]` at all also gives `None`,
+/// for optional overrides of values that have built-in defaults
+fn config_override<T: DeserializeOwned>(
+    dotted_path: &str,
+) -> Result<Option<T>> {
+    let config: Option<toml::Value> = toml_from_env("HUBRIS_APP_CONFIG")?;
+    lookup_override(config.as_ref(), dotted_path)
+}
+
+fn lookup_override<T: DeserializeOwned>(
+    config: Option<&toml::Value>,
+    dotted_path: &str,
+) -> Result<Option<T>> {
+    match config {
+        Some(config) => lookup_path(config, dotted_path),
+        None => Ok(None),
+    }
+}
+
+fn lookup_path<T: DeserializeOwned>(
+    config: &toml::Value,
+    dotted_path: &str,
+) -> Result<Option<T>> {
+    let mut value = config;
+    for segment in dotted_path.split('.') {
+        let next = match value {
+            toml::Value::Array(a) => {
+                segment.parse::<usize>().ok().and_then(|i| a.get(i))
+            }
+            _ => value.get(segment),
+        };
+        match next {
+            Some(v) => value = v,
+            None => return Ok(None),
+        }
+    }
+    value.clone().try_into().map(Some).with_context(|| {
+        format!("deserializing config value `{}`", dotted_path)
+    })
+}
+
+/// Pulls the app-wide configuration, with individual values overridden by
+/// `HUBRIS_CONFIG_SET`, for quick experiments without editing `app.toml`.
+///
+/// `HUBRIS_CONFIG_SET` is a semicolon-separated list of `dotted.path=value`
+/// assignments (e.g. `net.mtu=1400;net.name="test"`), where paths are as in
+/// `config_get` and each value is parsed as TOML, so strings must be quoted
+/// (and can't contain semicolons).  Assignments are applied in order.  It's
+/// an error to set a path that isn't already in the configuration, which
+/// catches typos.
+pub fn config_with_overrides<T: DeserializeOwned>() -> Result<T> {
+    let mut config: toml::Value = config()?;
+    if let Some(set) = maybe_env_var("HUBRIS_CONFIG_SET")? {
+        apply_overrides(&mut config, &set).context("in $HUBRIS_CONFIG_SET")?;
+    }
+    config
+        .try_into()
+        .context("deserializing overridden configuration")
+}
+
+fn apply_overrides(config: &mut toml::Value, set: &str) -> Result<()> {
+    for assignment in set.split(';').map(str::trim).filter(|a| !a.is_empty()) {
+        let (path, value) = assignment.split_once('=').ok_or_else(|| {
+            anyhow!("`{}` is not a `dotted.path=value` assignment", assignment)
+        })?;
+        let (path, value) = (path.trim(), value.trim());
+        let value = toml::from_str::<toml::Value>(&format!("v = {}", value))
+            .ok()
+            .and_then(|mut t| t.as_table_mut()?.remove("v"))
+            .ok_or_else(|| {
+                anyhow!(
+                    "`{}` is not a TOML value (strings must be quoted)",
+                    value
+                )
+            })?;
+
+        let mut target = &mut *config;
+        for segment in path.split('.') {
+            let next = match target {
+                toml::Value::Array(a) => {
+                    segment.parse::<usize>().ok().and_then(|i| a.get_mut(i))
+                }
+                toml::Value::Table(t) => t.get_mut(segment),
+                _ => None,
+            };
+            target = next.ok_or_else(|| {
+                anyhow!("can't set `{}`: there's no such config value", path)
+            })?;
+        }
+        *target = value;
+    }
+    Ok(())
+}
+
+/// Pulls the app-wide configuration, after checking that its top-level
+/// `config_version` field matches `expected`.  See `config` for more details.
+///
+/// Configuration without a `config_version` is treated as version 1, which
+/// predates the field.  A mismatch is an error, so that configuration written
+/// for an older (or newer) format fails clearly rather than misparsing.
+pub fn config_versioned<T: DeserializeOwned>(expected: u32) -> Result<T> {
+    let config: toml::Value = config()?;
+    check_config_version(&config, expected)?;
+    config.try_into().context("deserializing configuration")
+}
+
+fn check_config_version(config: &toml::Value, expected: u32) -> Result<()> {
+    let version = match config.get("config_version") {
+        None => 1,
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|&v| v > 0)
+            .ok_or_else(|| {
+                anyhow!("config_version must be a positive integer, not {}", v)
+            })?,
+    };
+    if version != expected {
+        bail!(
+            "app.toml [config] is version {}, but this build expects version \
+             {}; please run the migration to update it (and set \
+             `config_version = {}`)",
+            version,
+            expected,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Pulls the app-wide configuration, or `default` if the global `[config]`
+/// section is absent.  See `config` for more details.
+pub fn config_or<T: DeserializeOwned>(default: T) -> Result<T> {
+    Ok(toml_from_env("HUBRIS_APP_CONFIG")?.unwrap_or(default))
+}
+
+/// Pulls configuration that's split into layers across several environment
+/// variables (e.g. `HUBRIS_APP_CONFIG`, then `HUBRIS_BOARD_CONFIG`, then
+/// `HUBRIS_LOCAL_CONFIG`), merged in the order given.
+///
+/// Each layer is deep-merged over the ones before it, so later layers win:
+/// tables are merged key-by-key, recursively, while scalars and arrays in a
+/// later layer replace the earlier value wholesale (arrays are *not*
+/// concatenated).  Variables that aren't set are skipped, but it's an error
+/// if none of them are.
+pub fn config_layered<T: DeserializeOwned>(vars: &[&str]) -> Result<T> {
+    let mut layers = vec![];
+    for var in vars {
+        if let Some(layer) = toml_from_env::<toml::Value>(var)? {
+            layers.push(layer);
+        }
+    }
+    merge_layers(layers)
+        .ok_or_else(|| anyhow!("none of {} are set", quoted_list(vars)))?
+        .try_into()
+        .context("deserializing layered configuration")
+}
+
+/// Deep-merges each layer over the ones before it, per `merge_toml`
+fn merge_layers(layers: Vec<toml::Value>) -> Option<toml::Value> {
+    let mut layers = layers.into_iter();
+    let mut merged = layers.next()?;
+    for layer in layers {
+        merge_toml(&mut merged, layer);
+    }
+    Some(merged)
+}
+
+/// Pulls the task configuration. See `config` for more details.
+pub fn task_config<T: DeserializeOwned>() -> Result<T> {
+    let task_name = task_name()?;
+    task_maybe_config()?.ok_or_else(|| {
+        anyhow!(
+            "app.toml missing task config section [tasks.{}.config]",
+            task_name
+        )
+    })
+}
+
+/// Pulls the task configuration from the `[tasks.X.config]` section of the
+/// given file (in `app.toml` format), where `X` is `HUBRIS_TASK_NAME`.
+///
+/// This is meant for debugging a single task's build against a config file
+/// directly, rather than through the environment that xtask sets up; normal
+/// builds should use `task_config`.
+pub fn task_config_from_path<T: DeserializeOwned>(
+    path: &std::path::Path,
+) -> Result<T> {
+    read_task_config_file(path, &task_name()?)
+}
+
+fn read_task_config_file<T: DeserializeOwned>(
+    path: &std::path::Path,
+    task: &str,
+) -> Result<T> {
+    if !path.exists() {
+        // Still rebuild once the file appears
+        emit(&format!("rerun-if-changed={}", path.display()));
+        bail!("task config file {} does not exist", path.display());
+    }
+    let app = read_app_toml(path)?;
+    app.get("tasks")
+        .and_then(|t| t.get(task))
+        .and_then(|t| t.get("config"))
+        .ok_or_else(|| {
+            anyhow!("{} has no section [tasks.{}.config]", path.display(), task)
+        })?
+        .clone()
+        .try_into()
+        .with_context(|| {
+            format!(
+                "deserializing [tasks.{}.config] from {}",
+                task,
+                path.display()
+            )
+        })
+}
+
+/// Reads the name of the task being built from `HUBRIS_TASK_NAME`
+fn task_name() -> Result<String> {
+    crate::env_var("HUBRIS_TASK_NAME").context(
+        "HUBRIS_TASK_NAME is not set; task configuration is only available \
+         from the build script of a task being built by xtask",
+    )
+}
+
+/// Pulls the task configuration, or `None` if the configuration is not
+/// provided.
+pub fn task_maybe_config<T: DeserializeOwned>() -> Result<Option<T>> {
+    toml_from_env("HUBRIS_TASK_CONFIG")
+}
+
+/// Pulls the task configuration, or `T::default()` if the configuration is
+/// not provided.
+pub fn task_config_or_default<T: DeserializeOwned + Default>() -> Result<T> {
+    Ok(task_maybe_config()?.unwrap_or_default())
+}
+
+/// Pulls the app-wide configuration as an untyped tree, for build scripts
+/// that need to walk it generically rather than deserialize it into a fixed
+/// type.  Like `config`, this fails if there is no `[config]` section.
+pub fn config_value() -> Result<toml::Value> {
+    config()
+}
+
+/// Pulls the task configuration as an untyped tree, or `None` if the
+/// configuration is not provided.
+pub fn task_config_value() -> Result<Option<toml::Value>> {
+    task_maybe_config()
+}
+
+/// Pulls the task configuration, merged over shared defaults from the global
+/// `[config.defaults.<defaults_key>]` section.
+///
+/// Values in the task's own `[tasks.X.config]` take precedence; see
+/// `merge_toml` for the exact merge rules.  Either section may be absent,
+/// but not both.
+pub fn task_config_merged<T: DeserializeOwned>(
+    defaults_key: &str,
+) -> Result<T> {
+    let defaults = toml_from_env::<toml::Value>("HUBRIS_APP_CONFIG")?
+        .and_then(|c| c.get("defaults")?.get(defaults_key).cloned());
+    let task = task_maybe_config::<toml::Value>()?;
+    let merged = match (defaults, task) {
+        (Some(mut defaults), Some(task)) => {
+            merge_toml(&mut defaults, task);
+            defaults
+        }
+        (Some(v), None) | (None, Some(v)) => v,
+        (None, None) => bail!(
+            "app.toml has neither [config.defaults.{}] nor a task config \
+             section",
+            defaults_key
+        ),
+    };
+    merged
+        .try_into()
+        .context("deserializing merged task configuration")
+}
+
+/// Pulls the task configuration, applying any conditional subsections whose
+/// predicate matches the enabled features.
+///
+/// A conditional subsection is a table whose key is a Cargo-style cfg
+/// expression, e.g. `[tasks.X.config.'cfg(feature = "h743")']`.  The
+/// supported predicates are `feature = "..."` and `all(...)`, `any(...)`, and
+/// `not(...)` of other predicates.  Matching subsections are merged over the
+/// rest of the configuration (see `merge_toml`), in key order; all of them
+/// are removed before deserializing.
+pub fn task_config_conditional<T: DeserializeOwned>() -> Result<T> {
+    let config = task_config::<toml::Value>()?;
+    apply_conditional(config, &has_feature)?
+        .try_into()
+        .context("deserializing conditional task configuration")
+}
+
+fn apply_conditional(
+    config: toml::Value,
+    enabled: &dyn Fn(&str) -> bool,
+) -> Result<toml::Value> {
+    let toml::Value::Table(table) = config else {
+        return Ok(config);
+    };
+    let (conditional, rest): (Vec<_>, _) = table
+        .into_iter()
+        .partition(|(k, _)| k.starts_with("cfg(") && k.ends_with(')'));
+    let mut config = toml::Value::Table(rest.into_iter().collect());
+    for (key, section) in conditional {
+        let expr = &key["cfg(".len()..key.len() - 1];
+        let matched = CfgParser { rest: expr }
+            .parse(enabled)
+            .with_context(|| format!("in conditional section `{}`", key))?;
+        if !section.is_table() {
+            bail!("conditional section `{}` must be a table", key);
+        }
+        if matched {
+            merge_toml(&mut config, section);
+        }
+    }
+    Ok(config)
+}
+
+/// Evaluates a cfg expression (without the surrounding `cfg(...)`)
+///
+/// The whole expression is parsed, even once its value is known, so that
+/// errors don't depend on which features are enabled.
+struct CfgParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> CfgParser<'a> {
+    fn parse(mut self, enabled: &dyn Fn(&str) -> bool) -> Result<bool> {
+        let value = self.predicate(enabled)?;
+        if !self.rest.trim().is_empty() {
+            bail!("unexpected `{}` in cfg expression", self.rest.trim());
+        }
+        Ok(value)
+    }
+
+    fn predicate(&mut self, enabled: &dyn Fn(&str) -> bool) -> Result<bool> {
+        let name = self.ident()?;
+        match name {
+            "feature" => {
+                self.expect('=')?;
+                Ok(enabled(self.string()?))
+            }
+            "all" | "any" => {
+                self.expect('(')?;
+                let mut values = vec![];
+                while !self.eat(')') {
+                    values.push(self.predicate(enabled)?);
+                    if !self.eat(',') {
+                        self.expect(')')?;
+                        break;
+                    }
+                }
+                Ok(if name == "all" {
+                    values.into_iter().all(|v| v)
+                } else {
+                    values.into_iter().any(|v| v)
+                })
+            }
+            "not" => {
+                self.expect('(')?;
+                let value = self.predicate(enabled)?;
+                self.expect(')')?;
+                Ok(!value)
+            }
+            _ => bail!(
+                "unsupported cfg predicate `{}`; expected feature, all, any, \
+                 or not",
+                name
+            ),
+        }
+    }
+
+    fn ident(&mut self) -> Result<&'a str> {
+        self.rest = self.rest.trim_start();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            bail!("expected a cfg predicate at `{}`", self.rest);
+        }
+        let (ident, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(ident)
+    }
+
+    fn string(&mut self) -> Result<&'a str> {
+        self.expect('"')?;
+        let (s, rest) = self
+            .rest
+            .split_once('"')
+            .ok_or_else(|| anyhow!("unterminated string in cfg expression"))?;
+        self.rest = rest;
+        Ok(s)
+    }
+
+    /// Consumes `c` (after any whitespace) if it's next
+    fn eat(&mut self, c: char) -> bool {
+        match self.rest.trim_start().strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if !self.eat(c) {
+            bail!("expected `{}` at `{}`", c, self.rest.trim_start());
+        }
+        Ok(())
+    }
+}
+
+/// Pulls the task configuration, validating it against a JSON schema before
+/// deserializing it.
+///
+/// This catches misspelled `Option` fields (with `"additionalProperties":
+/// false`) and out-of-range values, reporting the path of each violation.
+/// Only the subset of JSON Schema described in the `schema` module is
+/// supported.  Requires the `schema` feature.
+#[cfg(feature = "schema")]
+pub fn task_config_validated<T: DeserializeOwned>(schema: &str) -> Result<T> {
+    validate_config(task_config()?, schema)
+}
+
+/// Checks `config` against `schema`, then deserializes it
+#[cfg(feature = "schema")]
+fn validate_config<T: DeserializeOwned>(
+    config: toml::Value,
+    schema: &str,
+) -> Result<T> {
+    let schema: serde_json::Value =
+        serde_json::from_str(schema).context("parsing config schema")?;
+    let json = serde_json::to_value(&config)
+        .context("converting task configuration to JSON")?;
+    let errors = schema::validate(&schema, &json)
+        .context("checking task configuration against its schema")?;
+    if !errors.is_empty() {
+        bail!(
+            "task configuration does not match its schema:\n  {}",
+            errors.join("\n  ")
+        );
+    }
+    config
+        .try_into()
+        .context("deserializing task configuration")
+}
+
+/// Pulls the task configuration, with overrides for the current Cargo profile
+/// applied.
+///
+/// When building with the `release` profile, the optional
+/// `[tasks.X.config.release]` table is merged over the rest of the task
+/// config (per `merge_toml`), and likewise for `debug`.  Both tables are then
+/// removed, so that `T` may use `deny_unknown_fields`.
+pub fn task_config_for_profile<T: DeserializeOwned>() -> Result<T> {
+    let config: toml::Value = task_config()?;
+    // Cargo tracks `PROFILE` itself, so this doesn't use `env_var`
+    let profile = std::env::var("PROFILE").context("reading $PROFILE")?;
+    apply_profile(config, &profile)?
+        .try_into()
+        .context("deserializing task configuration")
+}
+
+/// Names of the profile-specific subtables handled by `apply_profile`
+const PROFILE_TABLES: &[&str] = &["debug", "release"];
+
+fn apply_profile(config: toml::Value, profile: &str) -> Result<toml::Value> {
+    let toml::Value::Table(mut table) = config else {
+        bail!("task configuration is not a table");
+    };
+    let mut over = None;
+    for name in PROFILE_TABLES {
+        if let Some(t) = table.remove(*name) {
+            if *name == profile {
+                over = Some(t);
+            }
+        }
+    }
+    let mut config = toml::Value::Table(table);
+    if let Some(over) = over {
+        if !over.is_table() {
+            bail!("[{}] overrides must be a table", profile);
+        }
+        merge_toml(&mut config, over);
+    }
+    Ok(config)
+}
+
+/// Deep-merges `over` into `base`
+///
+/// Tables are merged recursively, with values from `over` winning when both
+/// have the same key; any other value (scalars and arrays alike) in `over`
+/// replaces the value in `base` wholesale.
+fn merge_toml(base: &mut toml::Value, over: toml::Value) {
+    match (base, over) {
+        (toml::Value::Table(base), toml::Value::Table(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(b) => merge_toml(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Returns a SHA-256 digest (as a hex string) of the raw app and task
+/// configuration, for checking that two images were built from identical
+/// configuration.
+///
+/// This hashes the strings in `HUBRIS_APP_CONFIG` and `HUBRIS_TASK_CONFIG`
+/// (or the files named by their `_FILE` variants) as-is, rather than anything
+/// parsed from them, so it's deterministic.
+pub fn config_digest() -> Result<String> {
+    let app = read_config_var("HUBRIS_APP_CONFIG")?;
+    let task = read_config_var("HUBRIS_TASK_CONFIG")?;
+    Ok(digest_strs(&[app.as_deref(), task.as_deref()]))
+}
+
+/// Exposes `config_digest` into `env!("HUBRIS_CONFIG_DIGEST")`.
+pub fn expose_config_digest() -> Result<()> {
+    emit(&format!(
+        "rustc-env=HUBRIS_CONFIG_DIGEST={}",
+        config_digest()?
+    ));
+    Ok(())
+}
+
+/// Returns a short digest (16 hex digits) of the assignment of task IDs, for
+/// detecting when the task layout changes between builds.
+///
+/// Unlike `config_digest`, this only depends on the names and order of the
+/// tasks in `HUBRIS_TASKS`, not on any configuration.
+pub fn task_layout_digest() -> Result<String> {
+    Ok(layout_digest(&try_task_ids()?))
+}
+
+/// Exposes `task_layout_digest` into `env!("HUBRIS_TASK_LAYOUT_DIGEST")`.
+pub fn expose_task_layout_digest() -> Result<()> {
+    emit(&format!(
+        "rustc-env=HUBRIS_TASK_LAYOUT_DIGEST={}",
+        task_layout_digest()?
+    ));
+    Ok(())
+}
+
+fn layout_digest(ids: &TaskIds) -> String {
+    let names: Vec<Option<&str>> = ids.names().map(Some).collect();
+    let mut digest = digest_strs(&names);
+    digest.truncate(16);
+    digest
+}
+
+/// Hashes a sequence of optional strings with SHA-256, returning a hex string
+///
+/// Each string is prefixed with its presence and length, so that (for
+/// example) moving text from one string to the next changes the digest.
+fn digest_strs(parts: &[Option<&str>]) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    for part in parts {
+        match part {
+            Some(s) => {
+                hasher.update([1]);
+                hasher.update((s.len() as u64).to_le_bytes());
+                hasher.update(s.as_bytes());
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Returns a map of task names to their IDs.
+///
+/// # Panics
+/// If `HUBRIS_TASKS` is missing, names the same task more than once, or
+/// contains a name that isn't usable in generated code; see `try_task_ids`
+pub fn task_ids() -> TaskIds {
+    try_task_ids().expect("invalid HUBRIS_TASKS")
+}
+
+/// Returns a map of task names to their IDs, or an error if `HUBRIS_TASKS`
+/// is missing, names the same task more than once, or contains an invalid
+/// task name (see `TaskIds::try_from_names`).
+pub fn try_task_ids() -> Result<TaskIds> {
+    let tasks = crate::env_var("HUBRIS_TASKS")?;
+    TaskIds::try_from_names(&split_task_names(&tasks))
+}
+
+/// Returns a map of task names to their IDs, checking the invariants that the
+/// kernel relies on: no name is repeated, and the given supervisor task is
+/// task 0 (see `TaskIds::supervisor_id`).
+pub fn task_ids_checked(supervisor: &str) -> Result<TaskIds> {
+    let ids = try_task_ids()?;
+    ids.supervisor_id(supervisor)
+        .context("checking HUBRIS_TASKS")?;
+    Ok(ids)
+}
+
+/// Returns the names of every task that the app could include, for generating
+/// tables (e.g. enums) that should stay the same across board variants that
+/// include different subsets of tasks.
+///
+/// This is read from `HUBRIS_ALL_TASKS` if it's set; otherwise, it falls back
+/// to the tasks actually in this image, from `HUBRIS_TASKS`.  Either way, the
+/// names are in the order given, which for `HUBRIS_TASKS` is task ID order.
+pub fn all_declared_task_names() -> Result<Vec<String>> {
+    let all = maybe_env_var("HUBRIS_ALL_TASKS")?;
+    let tasks = maybe_env_var("HUBRIS_TASKS")?;
+    declared_task_names(all.as_deref(), tasks.as_deref())
+}
+
+fn declared_task_names(
+    all: Option<&str>,
+    tasks: Option<&str>,
+) -> Result<Vec<String>> {
+    let names = all.or(tasks).ok_or_else(|| {
+        anyhow!("neither HUBRIS_ALL_TASKS nor HUBRIS_TASKS is set")
+    })?;
+    Ok(split_task_names(names)
+        .into_iter()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Exposes the number of tasks in the image, from the `HUBRIS_TASKS` envvar.
+///
+/// This sets both `env!("HUBRIS_TASK_COUNT")` and
+/// `cfg(hubris_task_count="...")`.
+pub fn expose_task_count() {
+    let tasks = crate::env_var("HUBRIS_TASKS").expect("missing HUBRIS_TASKS");
+    let count = split_task_names(&tasks).len();
+    emit(&format!("rustc-env=HUBRIS_TASK_COUNT={}", count));
+    emit(&format!("rustc-cfg=hubris_task_count=\"{}\"", count));
+}
+
+/// Splits the comma-separated `HUBRIS_TASKS` list.  An empty string is an
+/// empty list, rather than a single task with an empty name.
+fn split_task_names(tasks: &str) -> Vec<&str> {
+    if tasks.is_empty() {
+        vec![]
+    } else {
+        tasks.split(',').collect()
+    }
+}
+
+/// Map of task names to their IDs.
+pub struct TaskIds {
+    /// Task IDs, indexed by name
+    ids: BTreeMap<String, usize>,
+    /// Task names, indexed by ID
+    names: Vec<String>,
+}
+
+/// Checks that a task name can be turned into identifiers, `cfg` values, and
+/// file names without any surprises
+fn check_task_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        bail!(
+            "invalid task name `{}` in HUBRIS_TASKS; names must start with a \
+             letter or `_`, and contain only letters, digits, `_`, and `-`",
+            name
+        );
+    }
+    Ok(())
+}
+
+impl TaskIds {
+    /// Builds a map from a list of task names, in task ID order.
+    ///
+    /// This is what `task_ids` uses under the hood, but without reading
+    /// `HUBRIS_TASKS` from the environment.
+    ///
+    /// # Panics
+    /// If any name is repeated or invalid; see `try_from_names`
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> TaskIds {
+        Self::try_from_names(names).expect("invalid task names")
+    }
+
+    /// Builds a map from a list of task names, in task ID order, returning an
+    /// error if any name is repeated or isn't usable in generated code.
+    ///
+    /// Names must start with an ASCII letter or `_`, followed by ASCII
+    /// letters, digits, `_`, or `-`.
+    pub fn try_from_names<S: AsRef<str>>(names: &[S]) -> Result<TaskIds> {
+        let mut ids = BTreeMap::new();
+        for (i, name) in names.iter().enumerate() {
+            let name = name.as_ref();
+            check_task_name(name)?;
+            if ids.insert(name.to_string(), i).is_some() {
+                bail!("task `{}` appears more than once in HUBRIS_TASKS", name);
+            }
+        }
+        let names = names.iter().map(|n| n.as_ref().to_string()).collect();
+        Ok(TaskIds { ids, names })
+    }
+
+    /// Returns the number of tasks.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Checks whether there are no tasks at all.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Get the ID of a task by name.
+    pub fn get(&self, task_name: &str) -> Option<usize> {
+        self.ids.get(task_name).copied()
+    }
+
+    /// Checks whether there's a task with the given name.
+    pub fn contains(&self, task_name: &str) -> bool {
+        self.ids.contains_key(task_name)
+    }
+
+    /// Iterates over task names, in task ID order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+
+    /// Get the name of a task by ID.
+    pub fn id_to_name(&self, id: usize) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
+    }
+
+    /// Returns the ID of the supervisor task, which is named by the caller
+    /// (e.g. `jefe` in most apps, but `runner` in test images).
+    ///
+    /// The kernel treats task 0 as the supervisor, so this is an error if the
+    /// named task is missing or isn't task 0.
+    pub fn supervisor_id(&self, supervisor: &str) -> Result<usize> {
+        match self.get(supervisor) {
+            Some(0) => Ok(0),
+            Some(id) => bail!(
+                "supervisor task `{}` must be task 0, but is task {}",
+                supervisor,
+                id
+            ),
+            None => bail!("there is no supervisor task `{}`", supervisor),
+        }
+    }
+
+    /// Iterates over `(name, id)` pairs, in task ID order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), i))
+    }
+
+    /// Convert a list of task names into a list of task IDs, ordered the same.
+    pub fn names_to_ids<S>(&self, names: &[S]) -> Result<Vec<usize>>
+    where
+        S: AsRef<str>,
+    {
+        let mut ids = Vec::with_capacity(names.len());
+        let mut unknown = vec![];
+        for name in names {
+            let name = name.as_ref();
+            match self.get(name) {
+                Some(id) => ids.push(id),
+                None => unknown.push(name),
+            }
+        }
+        match unknown[..] {
+            [] => Ok(ids),
+            [name] => match self.suggest(name) {
+                Some(s) => Err(anyhow!(
+                    "unknown task `{}`; did you mean `{}`?",
+                    name,
+                    s
+                )),
+                None => Err(anyhow!("unknown task `{}`", name)),
+            },
+            _ => {
+                let unknown: Vec<String> = unknown
+                    .iter()
+                    .map(|name| match self.suggest(name) {
+                        Some(s) => {
+                            format!("`{}` (did you mean `{}`?)", name, s)
+                        }
+                        None => format!("`{}`", name),
+                    })
+                    .collect();
+                Err(anyhow!("unknown tasks: {}", unknown.join(", ")))
+            }
+        }
+    }
+
+    /// Convert a list of task names into the set of their task IDs, e.g. for
+    /// building a bitmask; duplicates are allowed.  Unknown names are
+    /// reported as by `names_to_ids`.
+    pub fn names_to_id_set<S>(
+        &self,
+        names: &[S],
+    ) -> Result<std::collections::BTreeSet<usize>>
+    where
+        S: AsRef<str>,
+    {
+        Ok(self.names_to_ids(names)?.into_iter().collect())
+    }
+
+    /// Finds the task name closest to `name`, if any is close enough to be
+    /// a plausible typo.
+    fn suggest(&self, name: &str) -> Option<&str> {
+        // Larger distances make for nonsense suggestions on short names
+        const MAX_DISTANCE: usize = 2;
+
+        self.names()
+            .map(|n| (edit_distance(name, n), n))
+            .filter(|(d, _)| *d <= MAX_DISTANCE)
+            .min_by_key(|(d, _)| *d)
+            .map(|(_, n)| n)
+    }
+
+    /// Helper function to convert a map of operation names to allowed callers
+    /// (by name) to a map of operation names to allowed callers (by task ID).
+    pub fn remap_allowed_caller_names_to_ids(
+        &self,
+        allowed_callers: &BTreeMap<String, Vec<String>>,
+    ) -> Result<BTreeMap<String, Vec<usize>>> {
+        allowed_callers
+            .iter()
+            .map(|(name, tasks)| {
+                let task_ids = self.names_to_ids(tasks)?;
+                Ok((name.clone(), task_ids))
+            })
+            .collect()
+    }
+
+    /// Like `remap_allowed_caller_names_to_ids`, but with each list of task
+    /// IDs sorted, so that the output doesn't depend on the order in which
+    /// callers are listed in `app.toml`.
+    ///
+    /// Listing the same caller twice for an operation is an error.
+    pub fn remap_allowed_caller_names_to_ids_sorted(
+        &self,
+        allowed_callers: &BTreeMap<String, Vec<String>>,
+    ) -> Result<BTreeMap<String, Vec<usize>>> {
+        let mut out =
+            self.remap_allowed_caller_names_to_ids(allowed_callers)?;
+        for (name, ids) in out.iter_mut() {
+            ids.sort_unstable();
+            if let Some(w) = ids.windows(2).find(|w| w[0] == w[1]) {
+                bail!(
+                    "task `{}` is listed more than once as an allowed caller \
+                     for `{}`",
+                    self.names[w[0]],
+                    name
+                );
+            }
+        }
+        Ok(out)
+    }
+
+    /// Like `remap_allowed_caller_names_to_ids`, but callers may also be
+    /// given as a prefix pattern ending in `*` (e.g. `net_*`), which expands
+    /// to every matching task in ID order.
+    ///
+    /// A pattern that doesn't match any task is an error.
+    pub fn remap_allowed_caller_patterns_to_ids(
+        &self,
+        allowed_callers: &BTreeMap<String, Vec<String>>,
+    ) -> Result<BTreeMap<String, Vec<usize>>> {
+        allowed_callers
+            .iter()
+            .map(|(name, patterns)| {
+                let task_ids = self
+                    .expand_patterns(patterns)
+                    .with_context(|| format!("allowed callers for `{name}`"))?;
+                Ok((name.clone(), task_ids))
+            })
+            .collect()
+    }
+
+    /// Resolves a list of task names and `prefix*` patterns into task IDs
+    fn expand_patterns(&self, patterns: &[String]) -> Result<Vec<usize>> {
+        let mut names = vec![];
+        for pattern in patterns {
+            match pattern.strip_suffix('*') {
+                Some(prefix) => {
+                    let before = names.len();
+                    names.extend(
+                        self.names.iter().filter(|n| n.starts_with(prefix)),
+                    );
+                    if names.len() == before {
+                        bail!("pattern `{}` does not match any task", pattern);
+                    }
+                }
+                None => names.push(pattern),
+            }
+        }
+        self.names_to_ids(&names)
+    }
+}
+
+/// Returns the Levenshtein distance between two strings, i.e. the number of
+/// single-character insertions, deletions, and substitutions to turn one into
+/// the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from a prefix of `a` to each prefix of `b`, one row at a time
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev + usize::from(ca != *cb);
+            prev = row[j + 1];
+            row[j + 1] = sub.min(prev + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Generates Rust source with a `pub const` holding the ID of each task, e.g.
+/// `pub const I2C_DRIVER: usize = 2;`, for use with `write_generated`.
+///
+/// Task names are converted to identifiers with `to_const_ident`; this returns
+/// an error if two tasks end up with the same identifier.
+pub fn generate_task_id_module() -> Result<String> {
+    task_id_module(&try_task_ids()?)
+}
+
+fn task_id_module(ids: &TaskIds) -> Result<String> {
+    let mut seen: BTreeMap<String, &str> = BTreeMap::new();
+    let mut out = String::new();
+    for (name, id) in ids.iter() {
+        let ident = to_const_ident(name);
+        if let Some(prev) = seen.insert(ident.clone(), name) {
+            bail!(
+                "tasks `{}` and `{}` both map to identifier `{}`",
+                prev,
+                name,
+                ident
+            );
+        }
+        out += &format!("pub const {}: usize = {};\n", ident, id);
+    }
+    Ok(out)
+}
+
+/// Converts an arbitrary name (e.g. from `app.toml`) into a valid Rust
+/// identifier, for use in generated code.
+///
+/// Any character other than an ASCII letter, digit, or `_` becomes `_`, and a
+/// name that doesn't start with a letter or `_` (including the empty name)
+/// gets a leading `_`; since `_` alone isn't an identifier, that (or the empty
+/// name) becomes `__`.  Rust keywords become raw identifiers (`r#type`),
+/// except for the few that can't be (e.g. `self`), which get a trailing `_`.
+pub fn to_ident(name: &str) -> String {
+    let out = sanitize_ident(name, |c| c);
+    if RAW_KEYWORDS.contains(&out.as_str()) {
+        format!("r#{}", out)
+    } else if NON_RAW_KEYWORDS.contains(&out.as_str()) {
+        out + "_"
+    } else {
+        out
+    }
+}
+
+/// Converts an arbitrary name into a SCREAMING_CASE identifier, e.g. for a
+/// `const`, with the same rules as `to_ident`.  (No keyword is uppercase, so
+/// there's no need to escape them.)
+pub fn to_const_ident(name: &str) -> String {
+    sanitize_ident(name, |c| c.to_ascii_uppercase())
+}
+
+fn sanitize_ident(name: &str, case: impl Fn(char) -> char) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                case(c)
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !out.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        out.insert(0, '_');
+    }
+    if out == "_" {
+        out.push('_');
+    }
+    out
+}
+
+/// Rust keywords (strict and reserved, through the 2021 edition) that can be
+/// used as raw identifiers
+const RAW_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const",
+    "continue", "do", "dyn", "else", "enum", "extern", "false", "final", "fn",
+    "for", "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move",
+    "mut", "override", "priv", "pub", "ref", "return", "static", "struct",
+    "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+/// Keywords that can't be raw identifiers
+const NON_RAW_KEYWORDS: &[&str] = &["crate", "self", "Self", "super"];
+
+/// Generates Rust source with a `pub const` holding the base address of each
+/// peripheral in the global `[config.peripherals]` table (mapping names to
+/// addresses), e.g. `pub const USART1_BASE: u32 = 0x40011000;`, for use with
+/// `write_generated`.
+///
+/// Names are converted to identifiers with `to_const_ident`.  Each
+/// address must be a non-zero, word-aligned, 32-bit value.
+pub fn generate_peripheral_constants() -> Result<String> {
+    let config: toml::Value = config()?;
+    let peripherals = config
+        .get("peripherals")
+        .ok_or_else(|| anyhow!("app.toml has no [config.peripherals] table"))?;
+    peripheral_constants(peripherals)
+}
+
+fn peripheral_constants(peripherals: &toml::Value) -> Result<String> {
+    let table = peripherals
+        .as_table()
+        .ok_or_else(|| anyhow!("[config.peripherals] must be a table"))?;
+    let mut seen: BTreeMap<String, &str> = BTreeMap::new();
+    let mut out = String::new();
+    for (name, addr) in table {
+        let addr = addr
+            .as_integer()
+            .and_then(|a| u32::try_from(a).ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "peripheral `{}` must have a 32-bit address, not {}",
+                    name,
+                    addr
+                )
+            })?;
+        if addr == 0 || addr % 4 != 0 {
+            bail!(
+                "peripheral `{}` has implausible base address {:#x} \
+                 (must be non-zero and word-aligned)",
+                name,
+                addr
+            );
+        }
+        let ident = format!("{}_BASE", to_const_ident(name));
+        if let Some(prev) = seen.insert(ident.clone(), name) {
+            bail!(
+                "peripherals `{}` and `{}` both map to identifier `{}`",
+                prev,
+                name,
+                ident
+            );
+        }
+        out += &format!("pub const {}: u32 = {:#x};\n", ident, addr);
+    }
+    Ok(out)
+}
+
+/// A size in bytes, for use in config structs
+///
+/// This deserializes from either an integer or a string with a binary unit
+/// suffix (`K`/`KiB`, `M`/`MiB`, or `G`/`GiB`), so `"8KiB"`, `"8K"`, and
+/// `8192` are all the same.  `K` means 1024 here, as in linker scripts, not
+/// 1000.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, suffix) = s.split_at(split);
+        let num: u64 = num
+            .parse()
+            .map_err(|_| anyhow!("size `{}` doesn't start with a number", s))?;
+        let unit = match suffix.trim_start() {
+            "" | "B" => 1,
+            "K" | "KiB" => 1 << 10,
+            "M" | "MiB" => 1 << 20,
+            "G" | "GiB" => 1 << 30,
+            other => bail!(
+                "unknown unit `{}` in size `{}`; expected K, KiB, M, MiB, G, \
+                 or GiB",
+                other,
+                s
+            ),
+        };
+        num.checked_mul(unit)
+            .map(ByteSize)
+            .ok_or_else(|| anyhow!("size `{}` is too large", s))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ByteSize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = ByteSize;
+
+            fn expecting(
+                &self,
+                f: &mut std::fmt::Formatter,
+            ) -> std::fmt::Result {
+                write!(f, "a size in bytes, or a string like \"8KiB\"")
+            }
+
+            fn visit_u64<E: serde::de::Error>(
+                self,
+                v: u64,
+            ) -> Result<ByteSize, E> {
+                Ok(ByteSize(v))
+            }
+
+            fn visit_i64<E: serde::de::Error>(
+                self,
+                v: i64,
+            ) -> Result<ByteSize, E> {
+                u64::try_from(v).map(ByteSize).map_err(|_| {
+                    E::custom(format!("size {} must not be negative", v))
+                })
+            }
+
+            fn visit_str<E: serde::de::Error>(
+                self,
+                v: &str,
+            ) -> Result<ByteSize, E> {
+                v.parse().map_err(|e| E::custom(format!("{:#}", e)))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// A memory region, as declared in the global `[config.memory]` table
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl MemoryRegion {
+    /// Returns the address just past the end of the region
+    fn end(&self) -> u64 {
+        u64::from(self.address) + u64::from(self.size)
+    }
+}
+
+/// Returns the memory regions declared in the global `[config.memory]` table,
+/// sorted by address.
+///
+/// This uses the same shape as a chip's `memory.toml`, with an array of
+/// tables for each named kind of memory:
+///
+/// ```toml
+/// [[config.memory.flash]]
+/// address = 0x08000000
+/// size = 1048576
+/// read = true
+/// execute = true
+/// ```
+///
+/// Permissions default to `false`, and other keys (e.g. `dma`) are ignored.
+/// It's an error for a region to be empty, to extend past the end of the
+/// address space, or to overlap another region.
+pub fn memory_regions() -> Result<Vec<MemoryRegion>> {
+    let config: toml::Value = config()?;
+    let memory = config
+        .get("memory")
+        .ok_or_else(|| anyhow!("app.toml has no [config.memory] table"))?;
+    parse_memory_regions(memory.clone())
+}
+
+fn parse_memory_regions(memory: toml::Value) -> Result<Vec<MemoryRegion>> {
+    #[derive(serde::Deserialize)]
+    struct Region {
+        address: u32,
+        size: u32,
+        #[serde(default)]
+        read: bool,
+        #[serde(default)]
+        write: bool,
+        #[serde(default)]
+        execute: bool,
+    }
+    let memory: BTreeMap<String, Vec<Region>> =
+        memory.try_into().context("parsing [config.memory]")?;
+
+    let mut out = vec![];
+    for (name, regions) in memory {
+        for r in regions {
+            let region = MemoryRegion {
+                name: name.clone(),
+                address: r.address,
+                size: r.size,
+                read: r.read,
+                write: r.write,
+                execute: r.execute,
+            };
+            if region.size == 0 {
+                bail!("memory region `{}` has size 0", name);
+            }
+            if region.end() > 1 << 32 {
+                bail!(
+                    "memory region `{}` at {:#x} extends past the end of \
+                     the address space",
+                    name,
+                    region.address
+                );
+            }
+            out.push(region);
+        }
+    }
+    out.sort_by_key(|r| r.address);
+    for w in out.windows(2) {
+        if w[0].end() > u64::from(w[1].address) {
+            bail!(
+                "memory regions `{}` ({:#x}..{:#x}) and `{}` ({:#x}..{:#x}) \
+                 overlap",
+                w[0].name,
+                w[0].address,
+                w[0].end(),
+                w[1].name,
+                w[1].address,
+                w[1].end()
+            );
+        }
+    }
+    Ok(out)
+}
+
+/// Generates a linker script with a `MEMORY` block for the regions in the
+/// global `[config.memory]` table (see `memory_regions`), for the caller to
+/// write to `OUT_DIR` and add to the link search path.
+///
+/// Each region is named after its kind in upper case (e.g. `FLASH`), so each
+/// kind may only appear once, and there must be a `flash` and a `ram`
+/// region.  These are what the `REGION_TEXT`, `REGION_RODATA`, `REGION_DATA`,
+/// and `REGION_BSS` aliases refer to.
+pub fn generate_linker_script() -> Result<String> {
+    linker_script(&memory_regions()?)
+}
+
+fn linker_script(regions: &[MemoryRegion]) -> Result<String> {
+    let mut seen = std::collections::BTreeSet::new();
+    let mut out = "MEMORY\n{\n".to_string();
+    // `memory_regions` has already sorted these and checked for overlaps
+    for r in regions {
+        let name = r.name.to_ascii_uppercase();
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("memory region `{}` can't be used in a linker script", name);
+        }
+        if !seen.insert(r.name.as_str()) {
+            bail!(
+                "there's more than one `{}` memory region, which a linker \
+                 script can't tell apart",
+                r.name
+            );
+        }
+        let attrs: String = [(r.read, 'r'), (r.write, 'w'), (r.execute, 'x')]
+            .into_iter()
+            .filter_map(|(set, c)| set.then_some(c))
+            .collect();
+        let attrs = match attrs.as_str() {
+            "" => String::new(),
+            a => format!(" ({})", a),
+        };
+        out += &format!(
+            "    {}{} : ORIGIN = {:#010x}, LENGTH = {:#010x}\n",
+            name, attrs, r.address, r.size
+        );
+    }
+    out += "}\n\n";
+    for (alias, kind) in [
+        ("REGION_TEXT", "flash"),
+        ("REGION_RODATA", "flash"),
+        ("REGION_DATA", "ram"),
+        ("REGION_BSS", "ram"),
+    ] {
+        if !seen.contains(kind) {
+            bail!("there's no `{}` memory region for {}", kind, alias);
+        }
+        out += &format!(
+            "REGION_ALIAS(\"{}\", {});\n",
+            alias,
+            kind.to_ascii_uppercase()
+        );
+    }
+    Ok(out)
+}
+
+/// Checks that no two tasks are granted overlapping writable memory.
+///
+/// Tasks are granted the shared regions named in their `uses` lists: the
+/// chip's peripherals from `chip.toml`, which are writable, and the app's
+/// `[extratext]` regions, which are read-only.  Overlapping regions are fine
+/// as long as at most one of the two tasks can write to them; otherwise,
+/// this returns an error naming both tasks and the overlapping range.
+///
+/// Note that this is stricter than the kernel, and some apps deliberately
+/// share a peripheral between tasks (e.g. `syscon` on the LPC55), so it's
+/// only suitable for apps that intend to keep tasks fully isolated.
+pub fn check_memory_isolation() -> Result<()> {
+    let (path, app) = app_toml_with_path()?;
+    let chip = read_chip_toml(&path, &app)?;
+    check_isolation(&app, &chip, &try_task_ids()?)
+}
+
+fn check_isolation(
+    app: &toml::Value,
+    chip: &toml::Value,
+    ids: &TaskIds,
+) -> Result<()> {
+    let region = |name: &str| -> Result<MemoryRegion> {
+        let (r, writable) = match (chip.get(name), app.get("extratext")) {
+            (Some(r), _) => (r, true),
+            (None, Some(extra)) if extra.get(name).is_some() => {
+                (&extra[name], false)
+            }
+            _ => bail!("no peripheral or extratext region `{}`", name),
+        };
+        let field = |key: &str| {
+            r.get(key)
+                .and_then(toml::Value::as_integer)
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| {
+                    anyhow!("region `{}` has no valid {}", name, key)
+                })
+        };
+        Ok(MemoryRegion {
+            name: name.to_string(),
+            address: field("address")?,
+            size: field("size")?,
+            read: true,
+            write: writable,
+            execute: !writable,
+        })
+    };
+
+    let mut granted = vec![];
+    for (name, _) in ids.iter() {
+        let uses = app_task(app, name)?
+            .get("uses")
+            .and_then(toml::Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for u in uses {
+            let u = u
+                .as_str()
+                .ok_or_else(|| anyhow!("task `{}` has invalid uses", name))?;
+            let r = region(u).with_context(|| format!("task `{}`", name))?;
+            granted.push((name, r));
+        }
+    }
+
+    for (i, (a, ra)) in granted.iter().enumerate() {
+        for (b, rb) in &granted[i + 1..] {
+            if a == b || !(ra.write || rb.write) {
+                continue;
+            }
+            let start = ra.address.max(rb.address);
+            let end = ra.end().min(rb.end());
+            if u64::from(start) < end {
+                bail!(
+                    "tasks `{}` and `{}` overlap at {:#x}..{:#x} (`{}` and \
+                     `{}`), which is writable",
+                    a,
+                    b,
+                    start,
+                    end,
+                    ra.name,
+                    rb.name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the map of task names to IDs as a JSON object (sorted by name), for
+/// external tools that want it without re-deriving it.
+pub fn write_task_manifest(path: &std::path::Path) -> Result<()> {
+    let json = task_manifest_json(&try_task_ids()?)?;
+    write_if_changed(path, &json)?;
+    Ok(())
+}
+
+/// Writes the task manifest (per `write_task_manifest`) into
+/// `OUT_DIR/tasks.json`, returning its path.
+pub fn write_task_manifest_to_out_dir() -> Result<std::path::PathBuf> {
+    write_generated("tasks.json", &task_manifest_json(&try_task_ids()?)?)
+}
+
+fn task_manifest_json(ids: &TaskIds) -> Result<String> {
+    let mut json = serde_json::to_string_pretty(&ids.ids)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// Parts of the build report written by `write_build_report`
+struct BuildReport {
+    tasks: Option<TaskIds>,
+    features: std::collections::BTreeSet<String>,
+    target: Option<String>,
+    git_version: Option<String>,
+    config_digest: Option<String>,
+}
+
+/// Writes a JSON report of how this was built, for provenance metadata: the
+/// map of task names to IDs, the enabled features, the target triple, the
+/// git version (see `expose_git_version`), and the config digest (see
+/// `config_digest`).
+///
+/// Every key is always present, so the report's shape is stable; anything
+/// that can't be determined (e.g. the git version outside of a checkout) is
+/// `null`.  Like `expose_git_version`, this reruns the build script when a
+/// new commit is made, so that the report's git version stays current.
+pub fn write_build_report(path: &std::path::Path) -> Result<()> {
+    track_git_head();
+    let report = BuildReport {
+        tasks: try_task_ids().ok(),
+        features: enabled_features(),
+        target: std::env::var("TARGET").ok(),
+        git_version: git_version(),
+        config_digest: config_digest().ok(),
+    };
+    write_if_changed(path, &build_report_json(&report)?)?;
+    Ok(())
+}
+
+fn build_report_json(report: &BuildReport) -> Result<String> {
+    let json = serde_json::json!({
+        "tasks": report.tasks.as_ref().map(|t| &t.ids),
+        "features": report.features,
+        "target": report.target,
+        "git_version": report.git_version,
+        "config_digest": report.config_digest,
+    });
+    let mut json = serde_json::to_string_pretty(&json)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// Returns a map from task ID to stack size in bytes, from the `stacksize` of
+/// each task in `app.toml`.
+///
+/// As in xtask, a task without its own `stacksize` uses the app-wide
+/// top-level `stacksize`.  If neither is set, the task gets `default`, or if
+/// that's `None`, this returns an error.  Each size must be a non-zero
+/// multiple of 8, as required for stack alignment on ARM.
+pub fn task_stack_sizes(default: Option<u32>) -> Result<BTreeMap<usize, u32>> {
+    stack_sizes(&app_toml()?, &try_task_ids()?, default)
+}
+
+fn stack_sizes(
+    app: &toml::Value,
+    ids: &TaskIds,
+    default: Option<u32>,
+) -> Result<BTreeMap<usize, u32>> {
+    let size = |v: &toml::Value, what: &str| -> Result<u32> {
+        v.as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| anyhow!("{} has invalid stacksize {}", what, v))
+    };
+    let app_default = match app.get("stacksize") {
+        Some(v) => Some(size(v, "app.toml")?),
+        None => None,
+    };
+    let mut out = BTreeMap::new();
+    for (name, id) in ids.iter() {
+        let what = format!("task `{}`", name);
+        let stack = match app_task(app, name)?.get("stacksize") {
+            Some(v) => size(v, &what)?,
+            None => app_default.or(default).ok_or_else(|| {
+                anyhow!("{} has no stacksize, and there's no default", what)
+            })?,
+        };
+        if stack == 0 || stack % 8 != 0 {
+            bail!(
+                "{} has stacksize {}, which is not a non-zero multiple of 8",
+                what,
+                stack
+            );
+        }
+        out.insert(id, stack);
+    }
+    Ok(out)
+}
+
+/// Boot-time flags for a task's kernel descriptor, from its `[tasks.X]`
+/// section in `app.toml`
+///
+/// These mirror the kernel's `TaskFlags`.  The only flag so far is
+/// `START_AT_BOOT`; there's no per-task restart disposition in `app.toml`,
+/// since the supervisor restarts every faulted task unless told otherwise at
+/// runtime.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct TaskFlags {
+    /// Set by `start = true`; tasks are not started by default
+    pub start_at_boot: bool,
+}
+
+/// Returns a map from task ID to the descriptor flags of each task in
+/// `app.toml`, using the kernel's defaults for omitted fields.
+pub fn task_descriptor_flags() -> Result<BTreeMap<usize, TaskFlags>> {
+    descriptor_flags(&app_toml()?, &try_task_ids()?)
+}
+
+fn descriptor_flags(
+    app: &toml::Value,
+    ids: &TaskIds,
+) -> Result<BTreeMap<usize, TaskFlags>> {
+    let mut out = BTreeMap::new();
+    for (name, id) in ids.iter() {
+        let task = app_task(app, name)?;
+        let flag = |key: &str, default: bool| match task.get(key) {
+            None => Ok(default),
+            Some(toml::Value::Boolean(b)) => Ok(*b),
+            Some(v) => Err(anyhow!(
+                "task `{}` has invalid {} {}; expected true or false",
+                name,
+                key,
+                v
+            )),
+        };
+        out.insert(
+            id,
+            TaskFlags {
+                start_at_boot: flag("start", false)?,
+            },
+        );
+    }
+    Ok(out)
+}
+
+/// Returns a map from IRQ number to the ID of the task that handles it and
+/// the notification mask posted to that task, from the `interrupts` table of
+/// each task in `app.toml`.
+///
+/// As in xtask, each interrupt is either an IRQ number or a
+/// `"peripheral.name"` reference to an interrupt in the chip's `chip.toml`.
+/// Its notification is either a 32-bit mask or the name of one of the task's
+/// `notifications` (see `notification_bits`); like xtask, this requires the
+/// mask for an IRQ given by number to have exactly one bit set.  It's an
+/// error for two tasks (or one task twice) to claim the same IRQ.
+pub fn interrupt_map() -> Result<BTreeMap<u32, (usize, u32)>> {
+    let (path, app) = app_toml_with_path()?;
+    let chip = read_chip_toml(&path, &app)?;
+    irq_routes(&app, &chip, &try_task_ids()?)
+}
+
+fn irq_routes(
+    app: &toml::Value,
+    chip: &toml::Value,
+    ids: &TaskIds,
+) -> Result<BTreeMap<u32, (usize, u32)>> {
+    let mut owners: BTreeMap<u32, &str> = BTreeMap::new();
+    let mut out = BTreeMap::new();
+    for (name, id) in ids.iter() {
+        let task = app_task(app, name)?;
+        let Some(interrupts) = task.get("interrupts") else {
+            continue;
+        };
+        let interrupts = interrupts.as_table().ok_or_else(|| {
+            anyhow!("task `{}` has an `interrupts` that isn't a table", name)
+        })?;
+        for (irq, notification) in interrupts {
+            let what = format!("task `{}` interrupt `{}`", name, irq);
+            let num = irq_number(chip, irq).context(what.clone())?;
+            let numeric_irq = irq.parse::<u32>().is_ok();
+            let mask = notification_mask(task, numeric_irq, notification)
+                .context(what)?;
+            if let Some(prev) = owners.insert(num, name) {
+                bail!(
+                    "IRQ {} is claimed by both task `{}` and task `{}`",
+                    num,
+                    prev,
+                    name
+                );
+            }
+            out.insert(num, (id, mask));
+        }
+    }
+    Ok(out)
+}
+
+/// Resolves an IRQ number or `"peripheral.name"` reference
+fn irq_number(chip: &toml::Value, irq: &str) -> Result<u32> {
+    if let Ok(num) = irq.parse() {
+        return Ok(num);
+    }
+    let Some((periph, name)) = irq.split_once('.') else {
+        bail!("not an IRQ number or a `peripheral.name` reference");
+    };
+    let periph = chip
+        .get(periph)
+        .ok_or_else(|| anyhow!("chip has no peripheral `{}`", periph))?;
+    let num = periph
+        .get("interrupts")
+        .and_then(|i| i.get(name))
+        .ok_or_else(|| anyhow!("peripheral has no interrupt `{}`", name))?;
+    num.as_integer()
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or_else(|| anyhow!("invalid IRQ number {}", num))
+}
+
+/// Resolves a notification mask, or the name of one of `task`'s
+/// `notifications`, and checks it as xtask does: it must fit in 32 bits, and
+/// for an IRQ given by number, it must have exactly one bit set
+fn notification_mask(
+    task: &toml::Value,
+    numeric_irq: bool,
+    notification: &toml::Value,
+) -> Result<u32> {
+    let mask = match notification {
+        toml::Value::String(name) => {
+            let bit = declared_notifications(task)?
+                .get(name)
+                .copied()
+                .ok_or_else(|| anyhow!("no notification named `{}`", name))?;
+            1 << bit
+        }
+        _ => notification
+            .as_integer()
+            .and_then(|m| u32::try_from(m).ok())
+            .ok_or_else(|| {
+                anyhow!("invalid notification mask {}", notification)
+            })?,
+    };
+    // A common mistake is giving a bit index rather than a mask
+    if numeric_irq && mask.count_ones() != 1 {
+        bail!(
+            "notification mask {:#b} must have exactly one bit set",
+            mask
+        );
+    }
+    Ok(mask)
+}
+
+/// Returns a map from notification name to bit number (not mask) for the
+/// task being built, from the `notifications` list in its `[tasks.X]`
+/// section of `app.toml`.
+///
+/// Bits are assigned in the order that notifications are declared, starting
+/// from bit 0.  It's an error to declare more than 32, or to declare the same
+/// name twice.  A task with no `notifications` gets an empty map.
+pub fn notification_bits() -> Result<BTreeMap<String, u32>> {
+    let task = task_name()?;
+    declared_notifications(app_task(&app_toml()?, &task)?)
+        .with_context(|| format!("reading notifications for [tasks.{}]", task))
+}
+
+fn declared_notifications(task: &toml::Value) -> Result<BTreeMap<String, u32>> {
+    let Some(list) = task.get("notifications") else {
+        return Ok(BTreeMap::new());
+    };
+    let list = list
+        .as_array()
+        .ok_or_else(|| anyhow!("`notifications` must be a list of names"))?;
+    if list.len() > 32 {
+        bail!(
+            "{} notifications are declared, but a task only has 32 bits",
+            list.len()
+        );
+    }
+    let mut out = BTreeMap::new();
+    for (bit, name) in (0..).zip(list) {
+        let name = name
+            .as_str()
+            .ok_or_else(|| anyhow!("notification {} is not a name", name))?;
+        if out.insert(name.to_string(), bit).is_some() {
+            bail!("notification `{}` is declared more than once", name);
+        }
+    }
+    Ok(out)
+}
+
+/// Returns a map from priority level to the IDs of the tasks at that level
+/// (in ID order), from the `priority` of each task in `app.toml`.
+///
+/// This also checks the rules that the kernel relies on: the supervisor (task
+/// 0) must be the only task at priority 0, and no task may be at the same or
+/// lower priority than the `idle` task.
+pub fn task_priorities() -> Result<BTreeMap<usize, Vec<usize>>> {
+    group_task_priorities(&app_toml()?, &try_task_ids()?)
+}
+
+fn group_task_priorities(
+    app: &toml::Value,
+    ids: &TaskIds,
+) -> Result<BTreeMap<usize, Vec<usize>>> {
+    let mut out: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    let mut priorities = Vec::with_capacity(ids.len());
+    for (name, id) in ids.iter() {
+        let priority = app_task(app, name)?
+            .get("priority")
+            .and_then(toml::Value::as_integer)
+            .ok_or_else(|| anyhow!("task `{}` has no priority", name))?;
+        let priority = usize::try_from(priority).map_err(|_| {
+            anyhow!("task `{}` has invalid priority {}", name, priority)
+        })?;
+        match (id, priority) {
+            (0, 0) => (),
+            (0, p) => bail!(
+                "supervisor task `{}` must be at priority 0, not {}",
+                name,
+                p
+            ),
+            (_, 0) => bail!(
+                "task `{}` is not the supervisor, but has priority 0",
+                name
+            ),
+            _ => (),
+        }
+        priorities.push((name, priority));
+        out.entry(priority).or_default().push(id);
+    }
+    if let Some(idle) = ids.get("idle") {
+        let idle_priority = priorities[idle].1;
+        for &(name, p) in &priorities {
+            if p >= idle_priority && name != "idle" {
+                bail!(
+                    "task `{}` has priority {}, which is >= idle priority {}",
+                    name,
+                    p,
+                    idle_priority
+                );
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Resolves the given task slots of the task being built to the IDs of the
+/// tasks they refer to, in the same order.
+///
+/// Slots are declared in the task's `task-slots` list in `app.toml`, either
+/// as a task name (a slot referring to the task of the same name) or as a
+/// `{ slot = "task" }` table.  It's an error for a slot not to be declared, or
+/// to refer to a task that isn't in this image.
+pub fn resolve_task_slots(slots: &[&str]) -> Result<Vec<usize>> {
+    let task = task_name()?;
+    let app = app_toml()?;
+    let declared = declared_task_slots(app_task(&app, &task)?)
+        .with_context(|| format!("reading task-slots for [tasks.{}]", task))?;
+    slots_to_ids(&declared, &try_task_ids()?, slots)
+        .with_context(|| format!("resolving task slots for [tasks.{}]", task))
+}
+
+/// Reads a task's `task-slots` list, as a map from slot name to task name
+fn declared_task_slots(task: &toml::Value) -> Result<BTreeMap<String, String>> {
+    let Some(list) = task.get("task-slots") else {
+        return Ok(BTreeMap::new());
+    };
+    let list = list
+        .as_array()
+        .ok_or_else(|| anyhow!("task-slots must be a list"))?;
+    let mut out = BTreeMap::new();
+    for entry in list {
+        let (slot, target) = match entry {
+            toml::Value::String(s) => (s.as_str(), s.as_str()),
+            toml::Value::Table(t) if t.len() == 1 => {
+                let (slot, target) = t.iter().next().unwrap();
+                let target = target.as_str().ok_or_else(|| {
+                    anyhow!("task slot `{}` must name a task", slot)
+                })?;
+                (slot.as_str(), target)
+            }
+            _ => bail!(
+                "task slot must be a task name or a single `slot = \"task\"` \
+                 pair, not {}",
+                entry
+            ),
+        };
+        out.insert(slot.to_string(), target.to_string());
+    }
+    Ok(out)
+}
+
+fn slots_to_ids(
+    declared: &BTreeMap<String, String>,
+    ids: &TaskIds,
+    slots: &[&str],
+) -> Result<Vec<usize>> {
+    slots
+        .iter()
+        .map(|slot| {
+            let target = declared.get(*slot).ok_or_else(|| {
+                anyhow!("task slot `{}` is not declared in task-slots", slot)
+            })?;
+            let id = ids
+                .names_to_ids(&[target])
+                .with_context(|| format!("task slot `{}`", slot))?;
+            Ok(id[0])
+        })
+        .collect()
+}
+
+/// Reads and parses the `app.toml` file that xtask passes in
+/// `HUBRIS_APP_TOML`, for helpers that need more of it than the `[config]`
+/// sections passed through the environment.
+fn app_toml() -> Result<toml::Value> {
+    Ok(app_toml_with_path()?.1)
+}
+
+/// Like `app_toml`, but also returns the path of the file that was parsed
+/// (after following any `inherit`), which relative paths in it are based on
+fn app_toml_with_path() -> Result<(std::path::PathBuf, toml::Value)> {
+    let path = crate::env_var("HUBRIS_APP_TOML")
+        .context("app.toml path is not set; is this being built by xtask?")?;
+    read_app_toml_with_path(std::path::Path::new(&path))
+}
+
+/// Reads and parses an `app.toml` file, tracking it for rebuilds
+///
+/// If the file `inherit`s from another one (to patch in extra features), this
+/// returns the inherited file, which is where everything else is declared.
+fn read_app_toml(path: &std::path::Path) -> Result<toml::Value> {
+    Ok(read_app_toml_with_path(path)?.1)
+}
+
+fn read_app_toml_with_path(
+    path: &std::path::Path,
+) -> Result<(std::path::PathBuf, toml::Value)> {
+    emit(&format!("rerun-if-changed={}", path.display()));
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    let app: toml::Value = toml::from_str(&text)
+        .with_context(|| format!("parsing {}", path.display()))?;
+    match app.get("inherit").and_then(toml::Value::as_str) {
+        Some(inherit) => read_app_toml_with_path(&app_dir(path).join(inherit)),
+        None => Ok((path.to_path_buf(), app)),
+    }
+}
+
+/// Returns the directory containing an `app.toml`
+fn app_dir(path: &std::path::Path) -> &std::path::Path {
+    path.parent().unwrap_or_else(|| std::path::Path::new(""))
+}
+
+/// Reads and parses the `chip.toml` peripheral map from the directory named
+/// by the `chip` key of an `app.toml`, tracking it for rebuilds
+fn read_chip_toml(
+    app_path: &std::path::Path,
+    app: &toml::Value,
+) -> Result<toml::Value> {
+    let chip = app
+        .get("chip")
+        .and_then(toml::Value::as_str)
+        .ok_or_else(|| anyhow!("app.toml has no `chip`"))?;
+    let path = app_dir(app_path).join(chip).join("chip.toml");
+    emit(&format!("rerun-if-changed={}", path.display()));
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Looks up the `[tasks.<name>]` table in a parsed `app.toml`
+fn app_task<'a>(app: &'a toml::Value, name: &str) -> Result<&'a toml::Value> {
+    app.get("tasks")
+        .and_then(|t| t.get(name))
+        .ok_or_else(|| anyhow!("app.toml has no section [tasks.{}]", name))
+}
+
+/// Parse the contents of an environment variable as toml.
+///
+/// If the variable isn't set, but the same name with `_FILE` appended is, the
+/// contents are read from the file that it names instead.
+///
+/// The contents may also be JSON, which is easier for tools to produce; this
+/// is detected by the contents starting with `{`, which is never valid TOML.
+///
+/// Returns:
+///
+/// - `Ok(Some(x))` if the environment variable is defined and the contents
+///   deserialized correctly.
+/// - `Ok(None)` if the environment variable is not defined.
+/// - `Err(e)` if deserialization failed or the environment variable did not
+///   contain UTF-8.
+fn toml_from_env<T: DeserializeOwned>(var: &str) -> Result<Option<T>> {
+    let Some(config) = cached_config(var)? else {
+        return Ok(None);
+    };
+
+    // Deserializing from the already-parsed TOML is much cheaper than parsing
+    // the text again.  If it fails, we fall back to the text, which gives
+    // errors with line numbers (and handles the few things that `toml` can
+    // only deserialize from text, e.g. enums written as tables).
+    if let Some(Ok(rval)) = config.value.clone().map(toml::Value::try_into) {
+        return Ok(Some(rval));
+    }
+    let rval = parse_config(&config.text, &config_section(var))?;
+    Ok(Some(rval))
+}
+
+/// Default limit on the size of a config environment variable, in bytes.
+/// This can be changed by setting `HUBRIS_CONFIG_MAX_BYTES`.
+pub const DEFAULT_CONFIG_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Returns the limit on the size of a config, from `HUBRIS_CONFIG_MAX_BYTES`
+fn config_max_bytes() -> Result<usize> {
+    Ok(env_var_parsed("HUBRIS_CONFIG_MAX_BYTES")?
+        .unwrap_or(DEFAULT_CONFIG_MAX_BYTES))
+}
+
+/// Checks a config string against the size limit, before we try to parse it
+fn check_config_size(var: &str, config: &str, max: usize) -> Result<()> {
+    if config.len() > max {
+        bail!(
+            "${} is {} bytes, which is over the limit of {} bytes \
+             (set HUBRIS_CONFIG_MAX_BYTES to change it)",
+            var,
+            config.len(),
+            max
+        );
+    }
+    Ok(())
+}
+
+/// A config environment variable, as read by `cached_config`
+struct CachedConfig {
+    /// Contents of the variable
+    text: String,
+    /// Parsed contents, if the variable holds valid TOML
+    value: Option<toml::Value>,
+}
+
+/// Config environment variables that have already been read in this build
+/// script, by name.  (Each build script is its own process, so these can't
+/// go stale.)
+static CONFIG_CACHE: Mutex<BTreeMap<String, Option<Arc<CachedConfig>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Reads a config environment variable, or returns it from `CONFIG_CACHE` if
+/// it has already been read.
+///
+/// Returns `Ok(None)` if the environment variable is not defined, and an
+/// error if it did not contain UTF-8.
+fn cached_config(var: &str) -> Result<Option<Arc<CachedConfig>>> {
+    cached_config_in(&CONFIG_CACHE, var, || read_config_var(var))
+}
+
+/// Returns config `var` from `cache`, or reads it with `read` and caches it
+fn cached_config_in(
+    cache: &Mutex<BTreeMap<String, Option<Arc<CachedConfig>>>>,
+    var: &str,
+    read: impl FnOnce() -> Result<Option<String>>,
+) -> Result<Option<Arc<CachedConfig>>> {
+    let mut cache = cache.lock().unwrap();
+    if let Some(config) = cache.get(var) {
+        return Ok(config.clone());
+    }
+
+    let config = match read().context("reading TOML from build environment")? {
+        Some(c) => c,
+        None => {
+            cache.insert(var.to_string(), None);
+            return Ok(None);
+        }
+    };
+
+    check_config_size(var, &config, config_max_bytes()?)?;
+
+    println!("--- toml for ${} ---", var);
+    println!("{}", config);
+    let value = match ConfigFormat::detect(&config) {
+        ConfigFormat::Toml => toml::from_str(&config).ok(),
+        ConfigFormat::Json => None,
+    };
+    let config = Some(Arc::new(CachedConfig {
+        text: config,
+        value,
+    }));
+    cache.insert(var.to_string(), config.clone());
+    Ok(config)
+}
+
+/// Reads a config environment variable, or if it isn't set, the file named by
+/// the same variable with `_FILE` appended (e.g. `HUBRIS_APP_CONFIG_FILE`)
+///
+/// The file is for configs too large to pass in the environment on some
+/// platforms.  Returns `Ok(None)` if neither variable is set.
+///
+/// A leading UTF-8 byte order mark, which some editors add and which the
+/// `toml` parser rejects, is stripped.
+fn read_config_var(var: &str) -> Result<Option<String>> {
+    Ok(read_config_source(var)?.map(strip_bom))
+}
+
+/// Removes a leading UTF-8 byte order mark, if there is one
+fn strip_bom(config: String) -> String {
+    match config.strip_prefix('\u{feff}') {
+        Some(rest) => rest.to_string(),
+        None => config,
+    }
+}
+
+fn read_config_source(var: &str) -> Result<Option<String>> {
+    let file_var = format!("{}_FILE", var);
+    config_source(
+        &file_var,
+        maybe_env_var(var)?,
+        maybe_env_var(&file_var)?,
+        config_max_bytes()?,
+    )
+}
+
+/// Picks the inline config if there is one, or else reads the file at
+/// `path`, which was named by `file_var`, if it's no larger than `max` bytes
+fn config_source(
+    file_var: &str,
+    inline: Option<String>,
+    path: Option<String>,
+    max: usize,
+) -> Result<Option<String>> {
+    if let Some(config) = inline {
+        return Ok(Some(config));
+    }
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    emit(&format!("rerun-if-changed={}", path));
+    let config = read_config_file(std::path::Path::new(&path), file_var, max)?;
+    Ok(Some(config))
+}
+
+/// Reads a config file named by `file_var`, checking it against the size
+/// limit before reading it, so that a huge file isn't read in only to be
+/// rejected
+fn read_config_file(
+    path: &std::path::Path,
+    file_var: &str,
+    max: usize,
+) -> Result<String> {
+    use std::io::Read;
+
+    let context = || {
+        format!(
+            "reading config from {} (named by ${})",
+            path.display(),
+            file_var
+        )
+    };
+    let len = std::fs::metadata(path).with_context(context)?.len();
+    if len > max as u64 {
+        bail!(
+            "{} (named by ${}) is {} bytes, which is over the limit of {} \
+             bytes (set HUBRIS_CONFIG_MAX_BYTES to change it)",
+            path.display(),
+            file_var,
+            len,
+            max
+        );
+    }
+    // The file could grow after we check it, so don't read past the limit
+    let mut config = String::new();
+    std::fs::File::open(path)
+        .and_then(|f| f.take(max as u64 + 1).read_to_string(&mut config))
+        .with_context(context)?;
+    check_config_size(file_var, &config, max)?;
+    Ok(config)
+}
+
+/// Describes the `app.toml` section held in a config environment variable,
+/// for use in error messages
+fn config_section(var: &str) -> String {
+    match var {
+        "HUBRIS_APP_CONFIG" => "[config]".to_string(),
+        // This is only used for error messages, so it doesn't need to be
+        // tracked with `env_var`
+        "HUBRIS_TASK_CONFIG" => match std::env::var("HUBRIS_TASK_NAME") {
+            Ok(task_name) => format!("[tasks.{}.config]", task_name),
+            Err(_) => "[tasks.<unknown>.config]".to_string(),
+        },
+        _ => format!("${}", var),
+    }
+}
+
+/// Format of a configuration string
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Guesses the format of a configuration string
+    fn detect(config: &str) -> Self {
+        if config.trim_start().starts_with('{') {
+            Self::Json
+        } else {
+            Self::Toml
+        }
+    }
+}
+
+/// Deserializes a configuration string, which may be either TOML or JSON
+///
+/// `section` names where the configuration came from, and is included (along
+/// with the line and column of the failure, and the text of that line, if
+/// known) in any error.
+fn parse_config<T: DeserializeOwned>(config: &str, section: &str) -> Result<T> {
+    match ConfigFormat::detect(config) {
+        ConfigFormat::Toml => {
+            toml::from_slice(config.as_bytes()).map_err(|e| {
+                let pos = e.line_col().map(|(line, col)| (line + 1, col + 1));
+                config_error(e, config, section, pos, "TOML")
+            })
+        }
+        ConfigFormat::Json => serde_json::from_str(config).map_err(|e| {
+            let pos = Some((e.line(), e.column())).filter(|&(l, _)| l > 0);
+            config_error(e, config, section, pos, "JSON")
+        }),
+    }
+}
+
+/// Wraps a deserialization error with the section and (1-based) position,
+/// quoting the offending line of `config`
+fn config_error<E>(
+    e: E,
+    config: &str,
+    section: &str,
+    pos: Option<(usize, usize)>,
+    format: &str,
+) -> anyhow::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let msg = match pos {
+        Some((line, col)) => {
+            let mut msg = format!(
+                "error in {} at line {}:{} ({})",
+                section, line, col, format
+            );
+            if let Some(text) = config.lines().nth(line - 1) {
+                // Show tabs and other invisible characters, which are the
+                // usual reason a line that looks fine doesn't parse
+                msg += &format!("\n    {} | {}", line, text.escape_debug());
+            }
+            msg
+        }
+        None => format!("error in {} ({})", section, format),
+    };
+    anyhow::Error::new(e).context(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// Directives captured by `capture_directives` on this thread
+        static CAPTURED: RefCell<Option<Vec<String>>> = RefCell::new(None);
+    }
+
+    /// Called by `emit`; records the directive and returns `true` if this
+    /// thread is capturing directives.
+    pub(super) fn capture(directive: &str) -> bool {
+        CAPTURED.with(|c| match c.borrow_mut().as_mut() {
+            Some(lines) => {
+                lines.push(format!("cargo:{}", directive));
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Runs `f`, returning the directives it emits instead of printing them
+    fn capture_directives(f: impl FnOnce()) -> Vec<String> {
+        CAPTURED.with(|c| *c.borrow_mut() = Some(vec![]));
+        f();
+        CAPTURED.with(|c| c.borrow_mut().take().unwrap())
+    }
+
+    #[derive(Debug, serde::Deserialize, PartialEq)]
+    struct TestConfig {
+        name: String,
+        count: u32,
+    }
+
+    #[test]
+    fn config_formats() {
+        let expected = TestConfig {
+            name: "spi1".to_string(),
+            count: 3,
+        };
+        let toml: TestConfig =
+            parse_config("name = \"spi1\"\ncount = 3\n", "[config]").unwrap();
+        assert_eq!(toml, expected);
+        let json: TestConfig =
+            parse_config(" {\"name\": \"spi1\", \"count\": 3}", "[config]")
+                .unwrap();
+        assert_eq!(json, expected);
+
+        let err = parse_config::<TestConfig>("{\"name\": 1}", "[config]")
+            .unwrap_err();
+        assert!(err.to_string().contains("JSON"));
+        let err =
+            parse_config::<TestConfig>("name = 1", "[config]").unwrap_err();
+        assert!(err.to_string().contains("TOML"));
+    }
+
+    #[test]
+    fn config_error_position() {
+        let err = parse_config::<TestConfig>(
+            "name = \"i2c\"\ncount = 3\nbaudrate =\t\n",
+            "[tasks.i2c_driver.config]",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("error in [tasks.i2c_driver.config] at line 3"),
+            "{err}"
+        );
+        assert!(
+            err.to_string().ends_with("\n    3 | baudrate =\\t"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn config_bom_stripped() {
+        let text = strip_bom("\u{feff}name = \"bom\"\ncount = 2\n".into());
+        let config: TestConfig = parse_config(&text, "[config]").unwrap();
+        assert_eq!(config.name, "bom");
+        assert_eq!(config.count, 2);
+        assert_eq!(strip_bom("a = 1".into()), "a = 1");
+    }
+
+    fn toml_value(s: &str) -> toml::Value {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merge_nested_tables() {
+        let mut base = toml_value(
+            r#"
+            speed = 100
+            [bus]
+            port = "i2c1"
+            pins = [1, 2]
+            [bus.timing]
+            rise = 10
+            fall = 20
+            "#,
+        );
+        let over = toml_value(
+            r#"
+            [bus]
+            pins = [3]
+            [bus.timing]
+            fall = 30
+            "#,
+        );
+        merge_toml(&mut base, over);
+        assert_eq!(
+            base,
+            toml_value(
+                r#"
+                speed = 100
+                [bus]
+                port = "i2c1"
+                pins = [3]
+                [bus.timing]
+                rise = 10
+                fall = 30
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn merge_replaces_mismatched_types() {
+        let mut base = toml_value("a = { b = 1 }\nc = [1, 2, 3]");
+        merge_toml(&mut base, toml_value("a = 5\nc = []"));
+        assert_eq!(base, toml_value("a = 5\nc = []"));
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn validated_config() {
+        let schema = r#"{
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string" },
+                "count": { "type": "integer", "maximum": 8 }
+            }
+        }"#;
+        let config: TestConfig =
+            validate_config(toml_value("name = \"spi1\"\ncount = 3"), schema)
+                .unwrap();
+        assert_eq!(config.count, 3);
+
+        let err = validate_config::<TestConfig>(
+            toml_value("name = \"spi1\"\ncount = 9\ncuont = 1"),
+            schema,
+        )
+        .unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("/count: 9 is more than the maximum"), "{err}");
+        assert!(err.contains("unknown field `cuont`"), "{err}");
+    }
+
+    #[test]
+    fn layered_merge() {
+        let base = toml_value(
+            "name = \"base\"\n\
+             pins = [1, 2, 3]\n\
+             [bus]\n\
+             speed = 100\n\
+             port = \"a\"\n",
+        );
+        let board = toml_value(
+            "pins = [4]\n\
+             [bus]\n\
+             speed = 400\n\
+             [board]\n\
+             rev = 2\n",
+        );
+        let local = toml_value("name = \"local\"\n[bus]\nport = \"b\"\n");
+        assert_eq!(
+            merge_layers(vec![base, board, local]).unwrap(),
+            toml_value(
+                "name = \"local\"\n\
+                 pins = [4]\n\
+                 [bus]\n\
+                 speed = 400\n\
+                 port = \"b\"\n\
+                 [board]\n\
+                 rev = 2\n"
+            )
+        );
+        assert_eq!(merge_layers(vec![]), None);
+    }
+
+    #[test]
+    fn chip_families() {
+        assert_eq!(chip_family_for("gimlet-c").unwrap(), "h7");
+        assert_eq!(chip_family_for("rot-carrier-2").unwrap(), "lpc55");
+        assert_eq!(chip_family_for("stm32f4-discovery").unwrap(), "f4");
+        let err = chip_family_for("apollo-1").unwrap_err();
+        assert!(err.to_string().contains("CHIP_FAMILIES"), "{err}");
+    }
+
+    #[test]
+    fn generated_names() {
+        assert!(check_generated_name("task_ids.rs").is_ok());
+        for name in ["", ".", "..", "../foo.rs", "gen/foo.rs", "/foo.rs"] {
+            assert!(check_generated_name(name).is_err(), "{name}");
+        }
+        assert!(check_generated_name("gen\\foo.rs").is_err());
+    }
+
+    #[test]
+    fn env_vars_tracked() {
+        assert_eq!(
+            capture_directives(|| track_env_vars(&["FOO", "BAR"])),
+            [
+                "cargo:rerun-if-env-changed=FOO",
+                "cargo:rerun-if-env-changed=BAR",
+            ]
+        );
+    }
+
+    #[test]
+    fn declared_tasks_fallback() {
+        assert_eq!(
+            declared_task_names(Some("jefe,net,spi,idle"), Some("jefe,idle"))
+                .unwrap(),
+            ["jefe", "net", "spi", "idle"]
+        );
+        assert_eq!(
+            declared_task_names(None, Some("jefe,idle")).unwrap(),
+            ["jefe", "idle"]
+        );
+        assert!(declared_task_names(None, None).is_err());
+    }
+
+    #[test]
+    fn config_sections_checked() {
+        let config = toml_value("[spi]\nbus = 1\n[cofig]\nx = 2\n[net]\n");
+        assert_eq!(unknown_sections(&config, &["spi", "net"]), ["cofig"]);
+        assert!(unknown_sections(&config, &["cofig", "net", "spi"]).is_empty());
+    }
+
+    #[test]
+    fn task_config_file() {
+        let dir = scratch_dir("task-config");
+        let path = dir.join("app.toml");
+        std::fs::write(
+            &path,
+            "[tasks.spi]\npriority = 2\n\
+             [tasks.spi.config]\nname = \"spi1\"\ncount = 3\n",
+        )
+        .unwrap();
+        let config: TestConfig = read_task_config_file(&path, "spi").unwrap();
+        assert_eq!(config.count, 3);
+
+        let err =
+            read_task_config_file::<TestConfig>(&path, "i2c").unwrap_err();
+        assert!(err.to_string().contains("no section [tasks.i2c.config]"));
+        let err = read_task_config_file::<TestConfig>(
+            &dir.join("missing.toml"),
+            "spi",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{err}");
+    }
+
+    #[test]
+    fn feature_arch_rules() {
+        let rules: &[(&str, &[&str])] = &[
+            ("dsp-accel", &["armv7m", "armv8m_main"]),
+            ("tz", &["armv8m"]),
+        ];
+        let on = |f: &str| f == "dsp-accel";
+        assert!(check_feature_arch(rules, MProfile::V7M, on).is_ok());
+        assert!(check_feature_arch(rules, MProfile::V8MMain, on).is_ok());
+        let err = check_feature_arch(rules, MProfile::V6M, on).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "feature `dsp-accel` is only supported on `armv7m`, \
+             `armv8m_main`, not armv6m"
+        );
+        assert!(check_feature_arch(rules, MProfile::V8MBase, on).is_err());
+        assert!(check_feature_arch(rules, MProfile::V6M, |_| false).is_ok());
+    }
+
+    #[test]
+    fn build_timer() {
+        let lines =
+            capture_directives(|| drop(BuildTimer::start("parse", true)));
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].starts_with("cargo:warning=parse took "),
+            "{lines:?}"
+        );
+        let lines =
+            capture_directives(|| drop(BuildTimer::start("parse", false)));
+        assert!(lines.is_empty(), "{lines:?}");
+    }
+
+    #[test]
+    fn idents() {
+        for (name, ident, const_ident) in [
+            ("foo", "foo", "FOO"),
+            ("1abc", "_1abc", "_1ABC"),
+            ("foo-bar", "foo_bar", "FOO_BAR"),
+            ("i2c.event", "i2c_event", "I2C_EVENT"),
+            ("type", "r#type", "TYPE"),
+            ("self", "self_", "SELF"),
+            ("", "__", "__"),
+            ("_", "__", "__"),
+            ("-", "__", "__"),
+        ] {
+            assert_eq!(to_ident(name), ident, "{name:?}");
+            assert_eq!(to_const_ident(name), const_ident, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn image_kinds() {
+        for kind in ImageKind::ALL {
+            assert_eq!(kind.to_string().parse::<ImageKind>().unwrap(), kind);
+        }
+        let err = "app".parse::<ImageKind>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown image kind `app` (expected one of `kernel`, `task`, \
+             `bootloader`)"
+        );
+    }
+
+    #[test]
+    fn serialization_formats() {
+        assert_eq!(serialization_format(None).unwrap(), SerFormat::Compact);
+        let config = toml_value("bus = 1\n");
+        assert_eq!(
+            serialization_format(Some(&config)).unwrap(),
+            SerFormat::Compact
+        );
+        let config = toml_value("serialization = \"debuggable\"\n");
+        assert_eq!(
+            serialization_format(Some(&config)).unwrap(),
+            SerFormat::Debuggable
+        );
+        let config = toml_value("serialization = \"json\"\n");
+        let err = serialization_format(Some(&config)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown serialization format `json` (expected one of \
+             `compact`, `debuggable`)"
+        );
+    }
+
+    #[test]
+    fn env_log_appended() {
+        let dir = scratch_dir("env-log");
+        let path = dir.join("env.log");
+        let lines = capture_directives(|| {
+            let board = Ok("gimlet-c".to_string());
+            record_tracked("HUBRIS_BOARD", &board, Some(&*path));
+            let secure = Err(std::env::VarError::NotPresent);
+            record_tracked("HUBRIS_SECURE", &secure, Some(&*path));
+            record_tracked("HUBRIS_TASKS", &secure, None);
+        });
+        assert_eq!(
+            lines,
+            [
+                "cargo:rerun-if-env-changed=HUBRIS_BOARD",
+                "cargo:rerun-if-env-changed=HUBRIS_SECURE",
+                "cargo:rerun-if-env-changed=HUBRIS_TASKS",
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "HUBRIS_BOARD=gimlet-c\nHUBRIS_SECURE\n"
+        );
+
+        // Failing to write the log is only a warning
+        let lines = capture_directives(|| {
+            record_tracked("HUBRIS_BOARD", &Ok("a".into()), Some(&*dir));
+        });
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[1].starts_with("cargo:warning=HUBRIS_TRACK_ENV_LOG: "),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn config_dotted_paths() {
+        let config = toml_value(
+            "[net.dhcp]\nretries = 3\n[[spi]]\nbus = \"spi2\"\n[[spi]]\nbus = \"spi4\"\n",
+        );
+        assert_eq!(
+            lookup_path::<u32>(&config, "net.dhcp.retries").unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            lookup_path::<String>(&config, "spi.1.bus")
+                .unwrap()
+                .as_deref(),
+            Some("spi4")
+        );
+        assert_eq!(
+            lookup_path::<u32>(&config, "net.dns.retries").unwrap(),
+            None
+        );
+        assert_eq!(lookup_path::<u32>(&config, "spi.2.bus").unwrap(), None);
+        assert_eq!(lookup_path::<u32>(&config, "spi.x").unwrap(), None);
+
+        let err = lookup_path::<bool>(&config, "net.dhcp.retries").unwrap_err();
+        assert!(err.to_string().contains("`net.dhcp.retries`"), "{err}");
+    }
+
+    #[test]
+    fn nvic_bits() {
+        assert_eq!(family_priority_bits("h7").unwrap(), 4);
+        assert_eq!(family_priority_bits("g0").unwrap(), 2);
+        assert!(family_priority_bits("rp2040").is_err());
+        // Every family that has a board has an entry
+        for (_, family) in CHIP_FAMILIES {
+            assert!(family_priority_bits(family).is_ok(), "{family}");
+        }
+        assert_eq!(check_priority_bits(3).unwrap(), 3);
+        assert!(check_priority_bits(0).is_err());
+        assert!(check_priority_bits(9).is_err());
+
+        // An app with no `[config]` (so no `HUBRIS_APP_CONFIG`, as in this
+        // test process) has no override, so gets the chip family's count
+        assert_eq!(config_override::<u8>("nvic_priority_bits").unwrap(), None);
+        let over = lookup_override::<u8>(None, "nvic_priority_bits").unwrap();
+        assert_eq!(over, None);
+        let config = toml_value("nvic_priority_bits = 2");
+        let over = lookup_override(Some(&config), "nvic_priority_bits");
+        assert_eq!(over.unwrap(), Some(2u8));
+    }
+
+    #[test]
+    fn custom_cfgs_declared() {
+        let lines = capture_directives(declare_custom_cfgs);
+        for line in [
+            "cargo:rustc-check-cfg=cfg(armv8m_main)",
+            "cargo:rustc-check-cfg=cfg(has_fpu)",
+            "cargo:rustc-check-cfg=cfg(target_board, values(any()))",
+            "cargo:rustc-check-cfg=cfg(chip_family, values(\"f3\", \"f4\", \
+             \"g0\", \"h7\", \"lpc55\"))",
+            "cargo:rustc-check-cfg=cfg(image_kind, values(\"kernel\", \
+             \"task\", \"bootloader\"))",
+        ] {
+            assert!(lines.iter().any(|l| l == line), "{line}");
+        }
+    }
+
+    #[test]
+    fn config_versions() {
+        let v2 = toml_value("config_version = 2\n[spi]\nbus = 1\n");
+        assert!(check_config_version(&v2, 2).is_ok());
+        let err = check_config_version(&v2, 3).unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "app.toml [config] is version 2, but this build expects \
+                 version 3; please run the migration"
+            ),
+            "{err}"
+        );
+
+        let unversioned = toml_value("[spi]\nbus = 1\n");
+        assert!(check_config_version(&unversioned, 1).is_ok());
+        assert!(check_config_version(&unversioned, 2).is_err());
+        let bad = toml_value("config_version = \"2\"\n");
+        assert!(check_config_version(&bad, 2).is_err());
+        // Versions start at 1, so 0 is never valid, even if it's expected
+        let zero = toml_value("config_version = 0\n");
+        let err = check_config_version(&zero, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "config_version must be a positive integer, not 0"
+        );
+    }
+
+    #[test]
+    fn locked_writes_serialize() {
+        let dir = scratch_dir("locked");
+        let path = dir.join("shared.rs");
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let contents = format!("// writer {}\n", i).repeat(4096);
+                    for _ in 0..20 {
+                        write_generated_locked(&path, &contents).unwrap();
+                        let seen = std::fs::read_to_string(&path).unwrap();
+                        assert_eq!(seen.len(), contents.len());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(!with_suffix(&path, ".lock").exists());
+
+        // A stale lock (e.g. from a crashed build) doesn't block writers
+        let lock = with_suffix(&path, ".lock");
+        std::fs::write(&lock, "").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let stale = std::time::Duration::from_millis(10);
+        assert!(write_locked(&path, "fresh", stale).unwrap());
+        assert!(!write_locked(&path, "fresh", stale).unwrap());
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn lock_wait_bounded() {
+        // A lock that can't be read can't be broken, even once it's stale
+        let lock = scratch_dir("lock-wait").join("shared.rs.lock");
+        std::fs::create_dir(&lock).unwrap();
+        let stale = std::time::Duration::from_millis(10);
+        let err = FileLock::acquire(&lock, stale).err().unwrap();
+        assert!(
+            err.to_string().ends_with(&format!(
+                "waiting for lock file {}",
+                lock.display()
+            )),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn stale_lock_broken_once() {
+        let dir = scratch_dir("stale-lock");
+        let path = dir.join("shared.rs");
+        let stale = std::time::Duration::from_millis(10);
+        for round in 0..20 {
+            std::fs::write(with_suffix(&path, ".lock"), "crashed").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+
+            // Two waiters race to break the same stale lock
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let threads: Vec<_> = (0..2)
+                .map(|i| {
+                    let (path, barrier) = (path.clone(), barrier.clone());
+                    std::thread::spawn(move || {
+                        let contents = format!("// {round} {i}\n").repeat(4096);
+                        barrier.wait();
+                        write_locked(&path, &contents, stale).unwrap();
+                        std::fs::read_to_string(&path).unwrap()
+                    })
+                })
+                .collect();
+            for t in threads {
+                let seen = t.join().unwrap();
+                assert_eq!(seen.len(), format!("// {round} 0\n").len() * 4096);
+            }
+            let leftovers: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .filter(|n| n != "shared.rs")
+                .collect();
+            assert!(leftovers.is_empty(), "{leftovers:?}");
+        }
+    }
+
+    #[test]
+    fn target_triples() {
+        for (target, arch, vendor, os, abi) in [
+            (
+                "thumbv6m-none-eabi",
+                "thumbv6m",
+                "unknown",
+                "none",
+                Some("eabi"),
+            ),
+            (
+                "thumbv7m-none-eabi",
+                "thumbv7m",
+                "unknown",
+                "none",
+                Some("eabi"),
+            ),
+            (
+                "thumbv7em-none-eabihf",
+                "thumbv7em",
+                "unknown",
+                "none",
+                Some("eabihf"),
+            ),
+            (
+                "thumbv8m.main-none-eabihf",
+                "thumbv8m.main",
+                "unknown",
+                "none",
+                Some("eabihf"),
+            ),
+            (
+                "thumbv8m.base-none-eabi",
+                "thumbv8m.base",
+                "unknown",
+                "none",
+                Some("eabi"),
+            ),
+            (
+                "riscv32imac-unknown-none-elf",
+                "riscv32imac",
+                "unknown",
+                "none",
+                Some("elf"),
+            ),
+            (
+                "x86_64-unknown-linux-gnu",
+                "x86_64",
+                "unknown",
+                "linux",
+                Some("gnu"),
+            ),
+            ("x86_64-apple-darwin", "x86_64", "apple", "darwin", None),
+        ] {
+            let t: TargetTriple = target.parse().unwrap();
+            assert_eq!(
+                (t.arch.as_str(), t.vendor.as_str(), t.os.as_str()),
+                (arch, vendor, os),
+                "{target}"
+            );
+            assert_eq!(t.abi.as_deref(), abi, "{target}");
+            assert_eq!(t.is_hard_float(), target.ends_with("hf"), "{target}");
+        }
+        for bad in ["", "thumbv7m", "thumbv7m-none", "a-b-c-d-e", "a--b"] {
+            assert!(bad.parse::<TargetTriple>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn warnings_collected() {
+        let mut warnings = WarningCollector::new();
+        warnings.push("no I2C devices");
+        warnings.push(format_args!("{} is deprecated", "spi_legacy"));
+        assert_eq!(
+            capture_directives(|| warnings.finish().unwrap()),
+            [
+                "cargo:warning=2 build warnings:",
+                "cargo:warning=  - no I2C devices",
+                "cargo:warning=  - spi_legacy is deprecated",
+            ]
+        );
+        let empty = WarningCollector::new();
+        assert!(capture_directives(|| empty.finish().unwrap()).is_empty());
+
+        let json = warnings_json("net", &["a".to_string()]).unwrap();
+        let back: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(back["net"], ["a"]);
+    }
+
+    #[test]
+    fn sysclk_range() {
+        assert_eq!(check_sysclk("h7", 400_000_000).unwrap(), 400_000_000);
+        assert_eq!(check_sysclk("g0", 16_000_000).unwrap(), 16_000_000);
+        let err = check_sysclk("g0", 400_000_000).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{err}");
+        assert!(check_sysclk("h7", 0).is_err());
+        for (_, family) in CHIP_FAMILIES {
+            assert!(check_sysclk(family, 8_000_000).is_ok(), "{family}");
+        }
+    }
+
+    #[test]
+    fn required_var_errors() {
+        use std::env::VarError;
+
+        let ok = required_value("HUBRIS_BOARD", Ok("gimletlet-2".into()));
+        assert_eq!(ok.unwrap(), "gimletlet-2");
+        let err = required_value("HUBRIS_BOARD", Err(VarError::NotPresent))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "required environment variable `HUBRIS_BOARD` is not set"
+        );
+        let bad = std::ffi::OsString::from("gimletlet-2");
+        let err =
+            required_value("HUBRIS_BOARD", Err(VarError::NotUnicode(bad)))
+                .unwrap_err();
+        assert!(err.to_string().contains("not valid UTF-8"), "{err}");
+    }
+
+    #[test]
+    fn byte_sizes() {
+        #[derive(serde::Deserialize)]
+        struct Sizes {
+            a: ByteSize,
+            b: ByteSize,
+            c: ByteSize,
+        }
+        let sizes: Sizes =
+            toml::from_str("a = 8192\nb = \"8K\"\nc = \"8KiB\"\n").unwrap();
+        assert_eq!(sizes.a, ByteSize(8192));
+        // `K` is binary, the same as `KiB`
+        assert_eq!(sizes.b, ByteSize(8192));
+        assert_eq!(sizes.c, ByteSize(8192));
+        assert_eq!("1M".parse::<ByteSize>().unwrap(), ByteSize(1 << 20));
+        assert_eq!("2 GiB".parse::<ByteSize>().unwrap(), ByteSize(2 << 30));
+
+        let err = "8KB".parse::<ByteSize>().unwrap_err();
+        assert!(err.to_string().contains("unknown unit `KB`"), "{err}");
+        assert!("KiB".parse::<ByteSize>().is_err());
+        assert!("99999999999999G".parse::<ByteSize>().is_err());
+        assert!(toml::from_str::<Sizes>("a = -1\nb = 1\nc = 1\n").is_err());
+    }
+
+    #[test]
+    fn config_cfgs_mapped() {
+        let config = toml_value(
+            r#"
+            dhcp = true
+            ipv6 = false
+            phy-kind = "ksz8463"
+            odd = "a\"b"
+            retries = 3
+            "#,
+        );
+        let cfgs = config_cfgs(
+            &config,
+            &["dhcp", "ipv6", "phy-kind", "odd", "missing"],
+        )
+        .unwrap();
+        assert_eq!(cfgs, ["dhcp", "phy_kind=\"ksz8463\"", "odd=\"a\\\"b\""]);
+
+        let err = config_cfgs(&config, &["retries"]).unwrap_err();
+        assert!(err.to_string().contains("type integer"), "{err}");
+        let config = toml_value("\"1st\" = true\n");
+        assert!(config_cfgs(&config, &["1st"]).is_err());
+    }
+
+    #[test]
+    fn clock_divisors() {
+        let d = nearest_divisor(64_000_000, 1_000_000).unwrap();
+        assert_eq!((d.divisor, d.achieved_hz), (64, 1_000_000));
+        assert_eq!(d.error_percent, 0.0);
+
+        // 16 MHz / 115200 is 138.9, which rounds up to 139
+        let d = nearest_divisor(16_000_000, 115_200).unwrap();
+        assert_eq!((d.divisor, d.achieved_hz), (139, 115_107));
+        assert!((d.error_percent + 0.08).abs() < 0.01, "{d:?}");
+
+        // The ideal divisor is 1.5, so neither 1 nor 2 is close enough
+        let err = nearest_divisor(1_000_000, 666_666).unwrap_err();
+        assert!(err.to_string().contains("nearest divisor, 2"), "{err}");
+        assert!(nearest_divisor(1_000_000, 2_000_000).is_err());
+        assert!(nearest_divisor(1_000_000, 0).is_err());
+    }
+
+    #[test]
+    fn task_output_names() {
+        let name = output_name("spi-driver", "sections.ld").unwrap();
+        assert_eq!(name, "task_spi_driver_sections.ld");
+        assert_eq!(output_name("spi_driver", "sections.ld").unwrap(), name);
+        assert_eq!(output_name("net", "map").unwrap(), "task_net_map");
+        assert!(output_name("net", "a/b").is_err());
+    }
+
+    #[test]
+    fn task_id_consts() {
+        let ids = TaskIds::from_names(&["jefe", "i2c-driver", "idle"]);
+        assert_eq!(
+            task_id_module(&ids).unwrap(),
+            "pub const JEFE: usize = 0;\n\
+             pub const I2C_DRIVER: usize = 1;\n\
+             pub const IDLE: usize = 2;\n"
+        );
+
+        let ids = TaskIds::from_names(&["jefe", "i2c-driver", "i2c_driver"]);
+        let err = task_id_module(&ids).unwrap_err();
+        assert!(err.to_string().contains("`I2C_DRIVER`"), "{err}");
+    }
+
+    #[test]
+    fn task_names() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "idle"]);
+        assert!(ids.contains("sys"));
+        assert!(!ids.contains("net"));
+        assert_eq!(ids.names().collect::<Vec<_>>(), ["jefe", "sys", "idle"]);
+    }
+
+    #[test]
+    fn peripheral_consts() {
+        let table = toml_value("usart1 = 0x4001_1000\ni2c-2 = 0x4000_5800\n");
+        assert_eq!(
+            peripheral_constants(&table).unwrap(),
+            "pub const I2C_2_BASE: u32 = 0x40005800;\n\
+             pub const USART1_BASE: u32 = 0x40011000;\n"
+        );
+
+        for bad in ["spi = 0x4001_3002", "spi = 0", "spi = 0x1_0000_0000"] {
+            let err = peripheral_constants(&toml_value(bad)).unwrap_err();
+            assert!(err.to_string().contains("`spi`"), "{err}");
+        }
+    }
+
+    #[test]
+    fn memory_region_layout() {
+        let memory = toml_value(
+            r#"
+            [[flash]]
+            address = 0x08000000
+            size = 0x100000
+            read = true
+            execute = true
+            [[ram]]
+            address = 0x20000000
+            size = 0x20000
+            read = true
+            write = true
+            [[ram]]
+            address = 0x10000000
+            size = 0x8000
+            read = true
+            dma = true
+            "#,
+        );
+        let regions = parse_memory_regions(memory).unwrap();
+        let names: Vec<_> = regions.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["flash", "ram", "ram"]);
+        assert_eq!(regions[0].address, 0x08000000);
+        assert!(regions[0].execute && !regions[0].write);
+        assert_eq!(regions[1].address, 0x10000000);
+
+        let overlap = toml_value(
+            "[[flash]]\naddress = 0x1000\nsize = 0x1000\n\
+             [[ram]]\naddress = 0x1800\nsize = 0x100\n",
+        );
+        let err = parse_memory_regions(overlap).unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{err}");
+
+        for bad in [
+            "[[ram]]\naddress = 0x1000\nsize = 0\n",
+            "[[ram]]\naddress = 0xffff_0000\nsize = 0x2_0000\n",
+        ] {
+            assert!(parse_memory_regions(toml_value(bad)).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn task_manifest_round_trip() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "hiffy", "idle"]);
+        let json = task_manifest_json(&ids).unwrap();
+        let back: BTreeMap<String, usize> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(back, ids.ids);
+        // Keys are sorted by name, for reproducibility
+        assert!(
+            json.find("\"hiffy\"").unwrap() < json.find("\"idle\"").unwrap()
+        );
+    }
+
+    #[test]
+    fn task_slots_resolved() {
+        let task = toml_value(
+            r#"task-slots = ["sys", { spi_driver = "spi2_driver" }, "jefe"]"#,
+        );
+        let declared = declared_task_slots(&task).unwrap();
+        let ids = TaskIds::from_names(&["jefe", "sys", "spi2_driver", "idle"]);
+        assert_eq!(
+            slots_to_ids(&declared, &ids, &["spi_driver", "jefe", "sys"])
+                .unwrap(),
+            [2, 0, 1]
+        );
+
+        let err = slots_to_ids(&declared, &ids, &["i2c_driver"]).unwrap_err();
+        assert!(err.to_string().contains("not declared"), "{err}");
+
+        let ids = TaskIds::from_names(&["jefe", "sys", "idle"]);
+        let err = slots_to_ids(&declared, &ids, &["spi_driver"]).unwrap_err();
+        assert!(format!("{err:#}").contains("`spi2_driver`"), "{err:#}");
+    }
+
+    #[test]
+    fn build_report_keys() {
+        let report = BuildReport {
+            tasks: Some(TaskIds::from_names(&["jefe", "idle"])),
+            features: ["h753".to_string()].into_iter().collect(),
+            target: Some("thumbv7em-none-eabihf".into()),
+            git_version: None,
+            config_digest: None,
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&build_report_json(&report).unwrap()).unwrap();
+        let keys: Vec<_> = json.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            [
+                "config_digest",
+                "features",
+                "git_version",
+                "target",
+                "tasks"
+            ]
+        );
+        assert_eq!(json["tasks"]["idle"], 1);
+        assert_eq!(json["features"][0], "h753");
+        assert!(json["git_version"].is_null());
+        assert!(json["config_digest"].is_null());
+    }
+
+    #[test]
+    fn stack_sizes_checked() {
+        let ids = TaskIds::from_names(&["jefe", "net", "idle"]);
+        let app = toml_value(
+            "[tasks.jefe]\nstacksize = 1536\n[tasks.net]\n\
+             [tasks.idle]\nstacksize = 256\n",
+        );
+        let err = stack_sizes(&app, &ids, None).unwrap_err();
+        assert!(err.to_string().contains("task `net`"), "{err}");
+        let sizes = stack_sizes(&app, &ids, Some(1024)).unwrap();
+        assert_eq!(
+            sizes.into_iter().collect::<Vec<_>>(),
+            [(0, 1536), (1, 1024), (2, 256)]
+        );
+
+        let app = toml_value(
+            "stacksize = 896\n[tasks.jefe]\n[tasks.net]\nstacksize = 2048\n\
+             [tasks.idle]\n",
+        );
+        let sizes = stack_sizes(&app, &ids, Some(1024)).unwrap();
+        assert_eq!(sizes[&0], 896);
+        assert_eq!(sizes[&1], 2048);
+
+        for bad in ["0", "1004", "-8"] {
+            let app = toml_value(&format!(
+                "stacksize = 896\n[tasks.jefe]\n[tasks.net]\n\
+                 stacksize = {bad}\n[tasks.idle]\n"
+            ));
+            let err = stack_sizes(&app, &ids, None).unwrap_err();
+            assert!(err.to_string().contains("task `net`"), "{err}");
+        }
+    }
+
+    #[test]
+    fn descriptor_flags_defaulted() {
+        let ids = TaskIds::from_names(&["jefe", "net", "idle"]);
+        let app = toml_value(
+            "[tasks.jefe]\nstart = true\n[tasks.net]\nstart = false\n\
+             [tasks.idle]\n",
+        );
+        let flags = descriptor_flags(&app, &ids).unwrap();
+        assert!(flags[&0].start_at_boot);
+        assert!(!flags[&1].start_at_boot);
+        assert_eq!(flags[&2], TaskFlags::default());
+
+        let app =
+            toml_value("[tasks.jefe]\n[tasks.net]\nstart = 1\n[tasks.idle]\n");
+        let err = descriptor_flags(&app, &ids).unwrap_err();
+        assert!(err.to_string().contains("task `net`"), "{err}");
+    }
+
+    #[test]
+    fn irq_routes_resolved() {
+        let ids = TaskIds::from_names(&["jefe", "spi", "uart", "idle"]);
+        let chip = toml_value(
+            "[spi1]\ninterrupts = { irq = 35 }\n\
+             [usart2]\ninterrupts = { irq = 38 }\n",
+        );
+        let app = toml_value(
+            r#"
+            [tasks.jefe]
+            [tasks.spi]
+            interrupts = { "spi1.irq" = 0b10 }
+            [tasks.uart]
+            notifications = ["timer", "usart-irq", "dma"]
+            interrupts = { "usart2.irq" = "usart-irq", "40" = "dma" }
+            [tasks.idle]
+            "#,
+        );
+        let routes = irq_routes(&app, &chip, &ids).unwrap();
+        assert_eq!(
+            routes.into_iter().collect::<Vec<_>>(),
+            [(35, (1, 0b10)), (38, (2, 0b10)), (40, (2, 0b100))]
+        );
+
+        for (irq, mask) in [
+            ("\"spi2.irq\"", "1"),
+            ("\"spi1.err\"", "1"),
+            ("\"41\"", "3"),
+            ("\"41\"", "\"rx\""),
+            ("\"usart2.irq\"", "-1"),
+        ] {
+            let app = toml_value(&format!(
+                "[tasks.jefe]\n[tasks.spi]\n[tasks.uart]\n\
+                 interrupts = {{ {irq} = {mask} }}\n[tasks.idle]\n"
+            ));
+            let err = irq_routes(&app, &chip, &ids).unwrap_err();
+            assert!(err.to_string().contains("task `uart`"), "{err}");
+        }
+    }
+
+    #[test]
+    fn irq_conflict() {
+        let ids = TaskIds::from_names(&["jefe", "spi", "uart", "idle"]);
+        let chip = toml_value("[spi1]\ninterrupts = { irq = 35 }\n");
+        let app = toml_value(
+            r#"
+            [tasks.jefe]
+            [tasks.spi]
+            interrupts = { "spi1.irq" = 1 }
+            [tasks.uart]
+            interrupts = { "35" = 1 }
+            [tasks.idle]
+            "#,
+        );
+        let err = irq_routes(&app, &chip, &ids).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "IRQ 35 is claimed by both task `spi` and task `uart`"
+        );
+    }
+
+    #[test]
+    fn linker_script_memory() {
+        let memory = toml_value(
+            r#"
+            [[ram]]
+            address = 0x20000000
+            size = 0x20000
+            read = true
+            write = true
+            [[flash]]
+            address = 0x08000000
+            size = 0x100000
+            read = true
+            execute = true
+            "#,
+        );
+        let script =
+            linker_script(&parse_memory_regions(memory).unwrap()).unwrap();
+        assert_eq!(
+            script,
+            "MEMORY\n{\n    \
+             FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 0x00100000\n    \
+             RAM (rw) : ORIGIN = 0x20000000, LENGTH = 0x00020000\n}\n\n\
+             REGION_ALIAS(\"REGION_TEXT\", FLASH);\n\
+             REGION_ALIAS(\"REGION_RODATA\", FLASH);\n\
+             REGION_ALIAS(\"REGION_DATA\", RAM);\n\
+             REGION_ALIAS(\"REGION_BSS\", RAM);\n"
+        );
+
+        // Check the structure of the MEMORY block line by line
+        let (memory, aliases) = script.split_once("}\n").unwrap();
+        let body = memory.strip_prefix("MEMORY\n{\n").unwrap();
+        for line in body.lines() {
+            let (decl, layout) = line.trim().split_once(" : ").unwrap();
+            let (origin, length) = layout.split_once(", ").unwrap();
+            assert!(decl.ends_with(')'), "{line}");
+            for (part, key) in [(origin, "ORIGIN"), (length, "LENGTH")] {
+                let hex = part.strip_prefix(key).unwrap();
+                let hex = hex.strip_prefix(" = 0x").unwrap();
+                u32::from_str_radix(hex, 16).unwrap();
+            }
+        }
+        assert!(!aliases.contains('{') && !aliases.contains('}'));
+
+        let ram_only = toml_value("[[ram]]\naddress = 0\nsize = 4\n");
+        let regions = parse_memory_regions(ram_only).unwrap();
+        let err = linker_script(&regions).unwrap_err();
+        assert!(err.to_string().contains("no `flash`"), "{err}");
+    }
+
+    #[test]
+    fn memory_isolation() {
+        let ids = TaskIds::from_names(&["jefe", "net", "sensor", "idle"]);
+        let chip = toml_value(
+            "[eth]\naddress = 0x40028000\nsize = 0x2000\n\
+             [eth_dma]\naddress = 0x40029000\nsize = 0x1000\n",
+        );
+        let app = |net: &str, sensor: &str| {
+            toml_value(&format!(
+                "[extratext.table]\naddress = 0x08100000\nsize = 0x1000\n\
+                 [tasks.jefe]\n[tasks.net]\nuses = {net}\n\
+                 [tasks.sensor]\nuses = {sensor}\n[tasks.idle]\n"
+            ))
+        };
+
+        // Read-only regions can be shared, and a task's own regions can
+        // overlap each other
+        check_isolation(
+            &app(r#"["eth", "eth_dma", "table"]"#, r#"["table"]"#),
+            &chip,
+            &ids,
+        )
+        .unwrap();
+
+        let err =
+            check_isolation(&app(r#"["eth"]"#, r#"["eth_dma"]"#), &chip, &ids)
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tasks `net` and `sensor` overlap at 0x40029000..0x4002a000 \
+             (`eth` and `eth_dma`), which is writable"
+        );
+
+        let err = check_isolation(&app("[]", r#"["spi1"]"#), &chip, &ids)
+            .unwrap_err();
+        assert!(err.to_string().contains("task `sensor`"), "{err}");
+    }
+
+    #[test]
+    fn notifications_assigned() {
+        let task = toml_value(r#"notifications = ["timer", "rx", "tx"]"#);
+        let bits = declared_notifications(&task).unwrap();
+        assert_eq!(
+            bits.into_iter().collect::<Vec<_>>(),
+            [("rx".into(), 1), ("timer".into(), 0), ("tx".into(), 2)]
+        );
+        assert!(declared_notifications(&toml_value("")).unwrap().is_empty());
+
+        let names: Vec<_> = (0..33).map(|i| format!("\"n{i}\"")).collect();
+        let task =
+            toml_value(&format!("notifications = [{}]", names.join(",")));
+        let err = declared_notifications(&task).unwrap_err();
+        assert!(err.to_string().contains("only has 32 bits"), "{err}");
+        let task =
+            toml_value(&format!("notifications = [{}]", names[..32].join(",")));
+        assert_eq!(declared_notifications(&task).unwrap()["n31"], 31);
+
+        let task = toml_value(r#"notifications = ["rx", "tx", "rx"]"#);
+        let err = declared_notifications(&task).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "notification `rx` is declared more than once"
+        );
+    }
+
+    #[test]
+    fn priorities_grouped() {
+        let app = toml_value(
+            r#"
+            [tasks.jefe]
+            priority = 0
+            [tasks.sys]
+            priority = 1
+            [tasks.i2c]
+            priority = 2
+            [tasks.spi]
+            priority = 2
+            [tasks.idle]
+            priority = 5
+            "#,
+        );
+        let ids = TaskIds::from_names(&["jefe", "sys", "i2c", "spi", "idle"]);
+        let groups = group_task_priorities(&app, &ids).unwrap();
+        let groups: Vec<_> = groups.into_iter().collect();
+        assert_eq!(
+            groups,
+            [(0, vec![0]), (1, vec![1]), (2, vec![2, 3]), (5, vec![4])]
+        );
+
+        let ids = TaskIds::from_names(&["sys", "jefe"]);
+        assert!(group_task_priorities(&app, &ids).is_err());
+        let inverted = toml_value(
+            "tasks.jefe.priority = 0\ntasks.i2c.priority = 3\n\
+             tasks.idle.priority = 3\n",
+        );
+        let ids = TaskIds::from_names(&["jefe", "i2c", "idle"]);
+        assert!(group_task_priorities(&inverted, &ids).is_err());
+        let ids = TaskIds::from_names(&["jefe", "net"]);
+        let err = group_task_priorities(&app, &ids).unwrap_err();
+        assert!(err.to_string().contains("[tasks.net]"), "{err}");
+    }
+
+    #[test]
+    fn app_toml_inherit() {
+        let dir = scratch_dir("inherit");
+        std::fs::write(
+            dir.join("base.toml"),
+            "name = \"base\"\n[tasks.jefe]\npriority = 0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lab.toml"),
+            "inherit = \"base.toml\"\n[patches]\nname = \"lab\"\n",
+        )
+        .unwrap();
+        let app = read_app_toml(&dir.join("lab.toml")).unwrap();
+        assert_eq!(app["name"].as_str(), Some("base"));
+        assert!(app_task(&app, "jefe").is_ok());
+    }
+
+    #[test]
+    fn config_includes() {
+        let dir = scratch_dir("include");
+        std::fs::write(
+            dir.join("spi.toml"),
+            "include = [\"common.toml\"]\n[spi]\nspeed = 100\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("common.toml"), "[spi]\nport = \"a\"\n")
+            .unwrap();
+        let config =
+            toml_value("include = [\"spi.toml\"]\n[spi]\nspeed = 400\n");
+        assert_eq!(
+            resolve_includes(config, Some(&dir), &mut vec![]).unwrap(),
+            toml_value("[spi]\nport = \"a\"\nspeed = 400\n")
+        );
+
+        std::fs::write(dir.join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+        std::fs::write(dir.join("b.toml"), "include = [\"./a.toml\"]\n")
+            .unwrap();
+        let config = toml_value("include = [\"a.toml\"]\n");
+        let err =
+            resolve_includes(config, Some(&dir), &mut vec![]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "include cycle: a.toml -> b.toml -> ./a.toml"
+        );
+
+        std::fs::write(dir.join("self.toml"), "include = [\"self.toml\"]\n")
+            .unwrap();
+        let config = toml_value("include = [\"self.toml\"]\n");
+        let err =
+            resolve_includes(config, Some(&dir), &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "include cycle: self.toml -> self.toml");
+    }
+
+    #[test]
+    fn config_from_file() {
+        const MAX: usize = DEFAULT_CONFIG_MAX_BYTES;
+        let path = scratch_dir("config-file").join("config.toml");
+        std::fs::write(&path, "name = \"file\"\ncount = 4\n").unwrap();
+        let file = Some(path.display().to_string());
+
+        let mut config = None;
+        let lines = capture_directives(|| {
+            config = config_source("V_FILE", None, file.clone(), MAX).unwrap();
+        });
+        assert_eq!(config.as_deref(), Some("name = \"file\"\ncount = 4\n"));
+        assert_eq!(
+            lines,
+            [format!("cargo:rerun-if-changed={}", path.display())]
+        );
+
+        // The inline variable takes precedence
+        let inline = Some("name = \"inline\"\ncount = 1\n".to_string());
+        let config =
+            config_source("V_FILE", inline.clone(), file, MAX).unwrap();
+        assert_eq!(config, inline);
+
+        assert_eq!(config_source("V_FILE", None, None, MAX).unwrap(), None);
+        let missing = Some(path.with_extension("nope").display().to_string());
+        capture_directives(|| {
+            let err = config_source("V_FILE", None, missing, MAX).unwrap_err();
+            assert!(format!("{err:#}").contains("$V_FILE"), "{err:#}");
+        });
+    }
+
+    #[test]
+    fn config_file_size_limit() {
+        let path = scratch_dir("config-file-size").join("config.toml");
+        std::fs::write(&path, "name = \"file\"\ncount = 4\n").unwrap();
+        assert_eq!(
+            read_config_file(&path, "V_FILE", 64).unwrap(),
+            "name = \"file\"\ncount = 4\n"
+        );
+        let err = read_config_file(&path, "V_FILE", 8).unwrap_err();
+        assert!(err.to_string().contains("is 24 bytes"), "{err}");
+        assert!(err.to_string().contains("$V_FILE"), "{err}");
+    }
+
+    #[test]
+    fn conditional_sections() {
+        let config = toml_value(
+            r#"
+            name = "spi"
+            count = 1
+            ['cfg(feature = "h743")']
+            count = 2
+            ['cfg(all(feature = "h753", not(feature = "h743")))']
+            name = "spi4"
+            "#,
+        );
+        let h743 = |f: &str| f == "h743";
+        let c: TestConfig = apply_conditional(config.clone(), &h743)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!((c.name.as_str(), c.count), ("spi", 2));
+
+        let h753 = |f: &str| f == "h753";
+        let c: TestConfig = apply_conditional(config, &h753)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!((c.name.as_str(), c.count), ("spi4", 1));
+
+        let none = |_: &str| false;
+        for expr in ["any()", "any(feature = \"a\",)", "not(any())"] {
+            CfgParser { rest: expr }.parse(&none).unwrap();
+        }
+        for expr in ["target_os = \"none\"", "feature", "not(feature = \"a\""] {
+            assert!(CfgParser { rest: expr }.parse(&none).is_err(), "{expr}");
+        }
+    }
+
+    #[test]
+    fn config_overrides() {
+        let mut config = toml_value(
+            r#"
+            [net]
+            mtu = 1500
+            name = "sp"
+            vlans = [1, 2]
+            "#,
+        );
+        apply_overrides(
+            &mut config,
+            r#"net.mtu=1400; net.vlans.1 = 7 ;net.name="test";"#,
+        )
+        .unwrap();
+        assert_eq!(config["net"]["mtu"].as_integer(), Some(1400));
+        assert_eq!(config["net"]["vlans"][1].as_integer(), Some(7));
+        assert_eq!(config["net"]["name"].as_str(), Some("test"));
+
+        let err = apply_overrides(&mut config, "net.mut=1400").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't set `net.mut`: there's no such config value"
+        );
+        for bad in ["net.mtu", "net.name=test", "net.vlans.2=3", "mtu.x=1"] {
+            assert!(apply_overrides(&mut config, bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn config_is_cached() {
+        let cache = Mutex::new(BTreeMap::new());
+        let read = |text: &str| {
+            let config =
+                cached_config_in(&cache, "V", || Ok(Some(text.into())))
+                    .unwrap()
+                    .unwrap();
+            config.value.clone().unwrap()
+        };
+        let first = read("name = \"a\"\ncount = 1");
+        let second = read("name = \"b\"\ncount = 2");
+        assert_eq!(first, second);
+        assert_eq!(second["name"].as_str(), Some("a"));
+
+        // A missing variable is cached too
+        let missing = cached_config_in(&cache, "W", || Ok(None)).unwrap();
+        assert!(missing.is_none());
+        let config =
+            cached_config_in(&cache, "W", || Ok(Some("a = 1".into()))).unwrap();
+        assert!(config.is_none());
+    }
+
+    #[test]
+    fn profile_overrides() {
+        let config = toml_value(
+            r#"
+            log = "info"
+            asserts = 1
+            [debug]
+            log = "trace"
+            [release]
+            asserts = 0
+            "#,
+        );
+        assert_eq!(
+            apply_profile(config.clone(), "debug").unwrap(),
+            toml_value("log = \"trace\"\nasserts = 1")
+        );
+        assert_eq!(
+            apply_profile(config, "release").unwrap(),
+            toml_value("log = \"info\"\nasserts = 0")
+        );
+
+        let plain = toml_value("log = \"info\"");
+        assert_eq!(apply_profile(plain.clone(), "release").unwrap(), plain);
+    }
+
+    #[test]
+    fn config_size_limit() {
+        assert!(check_config_size("V", "a = 1", 5).is_ok());
+        let err = check_config_size("V", "a = 10", 5).unwrap_err();
+        assert!(err.to_string().contains("6 bytes"), "{err}");
+        // The limit is in bytes, not characters
+        assert!(check_config_size("V", "a = \"\u{e9}\"", 7).is_err());
+    }
+
+    #[test]
+    fn env_expansion() {
+        let lookup = |name: &str| {
+            Ok(match name {
+                "KEY_DIR" => Some("/keys".to_string()),
+                "EMPTY" => Some(String::new()),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            expand_env("path = \"${KEY_DIR}/a.pem${EMPTY}\"", lookup).unwrap(),
+            "path = \"/keys/a.pem\""
+        );
+        assert_eq!(
+            expand_env("a = \"$${KEY_DIR} costs $5\"", lookup).unwrap(),
+            "a = \"${KEY_DIR} costs $5\""
+        );
+        let err = expand_env("a = \"${NOPE}\"", lookup).unwrap_err();
+        assert!(err.to_string().contains("`${NOPE}`"), "{err}");
+        assert!(expand_env("a = \"${KEY_DIR\"", lookup).is_err());
+    }
+
+    #[test]
+    fn env_expansion_in_values() {
+        let lookup = |name: &str| -> Result<Option<String>> {
+            Ok(match name {
+                "QUOTED" => Some("a\"b\\c\nd = 1".to_string()),
+                "KEY_DIR" => Some("/keys".to_string()),
+                _ => None,
+            })
+        };
+        let mut config = toml_value(
+            r#"
+            # ${NOPE} in a comment is ignored
+            name = "${QUOTED}"
+            "${NOPE}" = 1
+            [signing]
+            keys = ["${KEY_DIR}/a.pem", "plain"]
+            "#,
+        );
+        expand_env_values(&mut config, "", &lookup).unwrap();
+        let mut expected = toml_value(
+            r#"
+            "${NOPE}" = 1
+            [signing]
+            keys = ["/keys/a.pem", "plain"]
+            "#,
+        );
+        // The value is substituted verbatim, without adding any keys
+        expected.as_table_mut().unwrap().insert(
+            "name".to_string(),
+            toml::Value::String("a\"b\\c\nd = 1".to_string()),
+        );
+        assert_eq!(config, expected);
+
+        let mut config = toml_value("keys = [\"x\", \"${NOPE}\"]");
+        let err = expand_env_values(&mut config, "", &lookup).unwrap_err();
+        assert!(
+            format!("{err:#}").starts_with(
+                "in config value `keys.1`: config placeholder `${NOPE}`"
+            ),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn bool_values() {
+        for (s, v) in [
+            ("1", Some(true)),
+            ("TRUE", Some(true)),
+            ("yes", Some(true)),
+            ("On", Some(true)),
+            ("0", Some(false)),
+            ("false", Some(false)),
+            ("NO", Some(false)),
+            ("off", Some(false)),
+            ("", Some(false)),
+            ("maybe", None),
+            ("2", None),
+        ] {
+            assert_eq!(parse_bool(s), v, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn task_id_round_trip() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "idle"]);
+        for (name, id) in [("jefe", 0), ("sys", 1), ("idle", 2)] {
+            assert_eq!(ids.get(name), Some(id));
+            assert_eq!(ids.id_to_name(id), Some(name));
+        }
+        assert_eq!(ids.id_to_name(3), None);
+    }
+
+    #[test]
+    fn task_iter_order() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "hiffy", "idle"]);
+        let all: Vec<_> = ids.iter().collect();
+        assert_eq!(all, [("jefe", 0), ("sys", 1), ("hiffy", 2), ("idle", 3)]);
+    }
+
+    #[test]
+    fn supervisor() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "idle"]);
+        assert_eq!(ids.supervisor_id("jefe").unwrap(), 0);
+        let err = ids.supervisor_id("runner").unwrap_err();
+        assert_eq!(err.to_string(), "there is no supervisor task `runner`");
+
+        let ids = TaskIds::from_names(&["runner", "suite", "idle"]);
+        assert_eq!(ids.supervisor_id("runner").unwrap(), 0);
+
+        let ids = TaskIds::from_names(&["sys", "jefe", "idle"]);
+        let err = ids.supervisor_id("jefe").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "supervisor task `jefe` must be task 0, but is task 1"
+        );
+        let ids = TaskIds::from_names::<&str>(&[]);
+        assert!(ids.supervisor_id("jefe").is_err());
+    }
+
+    #[test]
+    fn task_count() {
+        assert_eq!(split_task_names(""), Vec::<&str>::new());
+        assert_eq!(split_task_names("jefe"), ["jefe"]);
+        assert_eq!(split_task_names("jefe,sys,idle"), ["jefe", "sys", "idle"]);
+
+        let ids = TaskIds::from_names(&split_task_names(""));
+        assert!(ids.is_empty());
+        assert_eq!(ids.len(), 0);
+        let ids = TaskIds::from_names(&split_task_names("jefe,idle"));
+        assert!(!ids.is_empty());
+        assert_eq!(ids.len(), 2);
+    }
+
+    #[test]
+    fn task_names_to_ids() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "i2c_driver", "idle"]);
+        assert_eq!(ids.names_to_ids(&["i2c_driver", "jefe"]).unwrap(), [2, 0]);
+        assert!(ids.names_to_ids(&["jefe", "uart"]).is_err());
+        let set = ids.names_to_id_set(&["idle", "jefe", "idle"]).unwrap();
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), [0, 3]);
+        let err = ids.names_to_id_set(&["uart", "idle", "spi"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown tasks: `uart`, `spi` (did you mean `sys`?)"
+        );
+
+        let callers: BTreeMap<String, Vec<String>> = [
+            (
+                "read".to_string(),
+                vec!["sys".to_string(), "jefe".to_string()],
+            ),
+            ("write".to_string(), vec!["idle".to_string()]),
+        ]
+        .into_iter()
+        .collect();
+        let remapped = ids.remap_allowed_caller_names_to_ids(&callers).unwrap();
+        assert_eq!(remapped["read"], [1, 0]);
+        assert_eq!(remapped["write"], [3]);
+    }
+
+    #[test]
+    fn task_names_duplicate() {
+        assert!(TaskIds::try_from_names(&["a", "b", "c"]).is_ok());
+        let err = TaskIds::try_from_names(&split_task_names("a,b,a"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("`a`"), "{err}");
+    }
+
+    #[test]
+    fn task_names_validated() {
+        assert!(TaskIds::try_from_names(&["jefe", "spi-driver", "_x2"]).is_ok());
+        for bad in ["net task", "drv/spi", "2nd", "", "uart.rx"] {
+            let err = TaskIds::try_from_names(&["jefe", bad]).err().unwrap();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("invalid task name `{bad}`")),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "task `sys` appears more than once")]
+    fn task_names_repeated() {
+        TaskIds::from_names(&["jefe", "sys", "idle", "sys"]);
+    }
+
+    #[test]
+    fn task_names_all_unknown() {
+        let ids = TaskIds::from_names(&["jefe", "spi_driver", "uart"]);
+        let err = ids
+            .names_to_ids(&["jefe", "spi_drv", "uart", "uartt"])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown tasks: `spi_drv`, `uartt` (did you mean `uart`?)"
+        );
+    }
+
+    #[test]
+    fn task_name_typo_suggested() {
+        let ids = TaskIds::from_names(&["jefe", "spi_driver", "uart"]);
+        let err = ids.names_to_ids(&["spi_drivr"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown task `spi_drivr`; did you mean `spi_driver`?"
+        );
+        let err = ids.names_to_ids(&["hiffy"]).unwrap_err();
+        assert_eq!(err.to_string(), "unknown task `hiffy`");
+    }
+
+    #[test]
+    fn edit_distances() {
+        for (a, b, d) in [
+            ("", "", 0),
+            ("", "uart", 4),
+            ("uart", "uart", 0),
+            ("uartt", "uart", 1),
+            ("spi_drivr", "spi_driver", 1),
+            ("sys", "spi", 2),
+            ("kitten", "sitting", 3),
+        ] {
+            assert_eq!(edit_distance(a, b), d, "{a} {b}");
+            assert_eq!(edit_distance(b, a), d, "{b} {a}");
+        }
+    }
+
+    #[test]
+    fn env_values_parsed() {
+        assert_eq!(parse_env_value::<usize>("K", "4096").unwrap(), 4096);
+        assert_eq!(
+            parse_env_value::<std::net::Ipv4Addr>("K", "10.0.0.1").unwrap(),
+            std::net::Ipv4Addr::new(10, 0, 0, 1)
+        );
+        let err = parse_env_value::<u32>("HUBRIS_HEAP_SIZE", "4k").unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("$HUBRIS_HEAP_SIZE") && msg.contains("`4k`"));
+
+        assert!(parse_env_option::<u32>("K", None).unwrap().is_none());
+        let value = parse_env_option::<u32>("K", Some("17".into())).unwrap();
+        assert_eq!(value, Some(17));
+        assert!(parse_env_option::<u32>("K", Some("x".into())).is_err());
+    }
+
+    #[test]
+    fn env_list_split() {
+        assert_eq!(split_list("a, b ,,c,"), ["a", "b", "c"]);
+        assert_eq!(split_list(""), Vec::<String>::new());
+        assert_eq!(split_list(" , "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn callers_sorted() {
+        let ids = TaskIds::from_names(&["jefe", "sys", "hiffy"]);
+        let callers = |list: &[&str]| -> BTreeMap<String, Vec<String>> {
+            [(
+                "op".to_string(),
+                list.iter().map(|s| s.to_string()).collect(),
+            )]
+            .into_iter()
+            .collect()
+        };
+        let a = ids
+            .remap_allowed_caller_names_to_ids_sorted(&callers(&[
+                "hiffy", "jefe",
+            ]))
+            .unwrap();
+        let b = ids
+            .remap_allowed_caller_names_to_ids_sorted(&callers(&[
+                "jefe", "hiffy",
+            ]))
+            .unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a["op"], [0, 2]);
+
+        let err = ids
+            .remap_allowed_caller_names_to_ids_sorted(&callers(&[
+                "sys", "jefe", "sys",
+            ]))
+            .unwrap_err();
+        assert!(err.to_string().contains("`sys`"), "{err}");
+    }
+
+    #[test]
+    fn caller_patterns() {
+        let ids =
+            TaskIds::from_names(&["jefe", "net", "net_a", "sys", "net_b"]);
+        let callers: BTreeMap<String, Vec<String>> = [(
+            "send".to_string(),
+            vec!["jefe".to_string(), "net_*".to_string()],
+        )]
+        .into_iter()
+        .collect();
+        let remapped =
+            ids.remap_allowed_caller_patterns_to_ids(&callers).unwrap();
+        assert_eq!(remapped["send"], [0, 2, 4]);
+
+        let callers: BTreeMap<String, Vec<String>> =
+            [("send".to_string(), vec!["nte_*".to_string()])]
+                .into_iter()
+                .collect();
+        let err = ids
+            .remap_allowed_caller_patterns_to_ids(&callers)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("`nte_*`"), "{err:#}");
+    }
+
+    #[test]
+    fn m_profile_from_target() {
+        for (target, profile, cfgs) in [
+            ("thumbv6m-none-eabi", MProfile::V6M, &["armv6m"][..]),
+            ("thumbv7m-none-eabi", MProfile::V7M, &["armv7m"]),
+            ("thumbv7em-none-eabihf", MProfile::V7M, &["armv7m"]),
+            (
+                "thumbv8m.base-none-eabi",
+                MProfile::V8MBase,
+                &["armv8m", "armv8m_base"],
+            ),
+            (
+                "thumbv8m.main-none-eabihf",
+                MProfile::V8MMain,
+                &["armv8m", "armv8m_main"],
+            ),
+        ] {
+            assert_eq!(MProfile::from_target(target).unwrap(), profile);
+            assert_eq!(profile.cfgs(), cfgs, "{target}");
+        }
+        assert!(MProfile::from_target("x86_64-unknown-linux-gnu").is_err());
+    }
+
+    #[test]
+    fn isa_from_target() {
+        assert_eq!(
+            isa_cfgs("thumbv7em-none-eabihf").unwrap(),
+            ["armv7m", "isa=\"armv7m\""]
+        );
+        assert_eq!(
+            isa_cfgs("thumbv8m.main-none-eabihf").unwrap(),
+            ["armv8m", "armv8m_main", "isa=\"armv8m\""]
+        );
+        assert_eq!(
+            isa_cfgs("thumbv8m.base-none-eabi").unwrap(),
+            ["armv8m", "armv8m_base", "isa=\"armv8m\""]
+        );
+        assert_eq!(
+            isa_cfgs("riscv32imac-unknown-none-elf").unwrap(),
+            ["riscv32", "isa=\"riscv32imac\""]
+        );
+        assert_eq!(
+            isa_cfgs("riscv32imc-unknown-none-elf").unwrap(),
+            ["riscv32", "isa=\"riscv32imc\""]
+        );
+        let err = isa_cfgs("x86_64-unknown-linux-gnu").unwrap_err();
+        assert!(err.to_string().contains("x86_64-unknown-linux-gnu"));
+    }
+
+    /// Returns a fresh scratch directory for tests that touch the filesystem
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "build-util-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn generated_only_written_on_change() {
+        let path = scratch_dir("write").join("gen.rs");
+        assert!(write_if_changed(&path, "const A: u32 = 1;").unwrap());
+        assert!(!write_if_changed(&path, "const A: u32 = 1;").unwrap());
+        assert!(write_if_changed(&path, "const A: u32 = 2;").unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "const A: u32 = 2;"
+        );
+    }
+
+    #[test]
+    fn task_layout_digest_is_stable() {
+        let digest =
+            |names: &[&str]| layout_digest(&TaskIds::from_names(names));
+        let a = digest(&["jefe", "sys", "spi", "idle"]);
+        assert_eq!(a.len(), 16);
+        assert_eq!(a, digest(&["jefe", "sys", "spi", "idle"]));
+        assert_ne!(a, digest(&["jefe", "spi", "sys", "idle"]));
+        assert_ne!(a, digest(&["jefe", "sys", "spi"]));
+    }
+
+    #[test]
+    fn config_digest_is_stable() {
+        let a = digest_strs(&[Some("x = 1"), Some("y = 2")]);
+        assert_eq!(a, digest_strs(&[Some("x = 1"), Some("y = 2")]));
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, digest_strs(&[Some("x = 1y"), Some(" = 2")]));
+        assert_ne!(a, digest_strs(&[Some("x = 1"), None]));
+        assert_ne!(digest_strs(&[Some(""), None]), digest_strs(&[None, None]));
+    }
+
+    #[test]
+    fn features_one_of() {
+        let names = ["h743", "h753", "h7b3"];
+        let enabled =
+            |set: &'static [&'static str]| move |n: &str| set.contains(&n);
+        assert_eq!(
+            one_enabled(&names, enabled(&["h753", "itm"])).unwrap(),
+            "h753"
+        );
+        let err = one_enabled(&names, enabled(&["itm"])).unwrap_err();
+        assert!(err.to_string().starts_with("exactly one"), "{err}");
+        let err = one_enabled(&names, enabled(&["h743", "h753"])).unwrap_err();
+        assert!(err.to_string().ends_with("`h743`, `h753`"), "{err}");
+    }
+
+    #[test]
+    fn features_from_env() {
+        let vars = ["CARGO_FEATURE_H753", "CARGO_FEATURE_FOO_BAR", "TARGET"];
+        let features = features_from_vars(vars.iter().map(|v| v.to_string()));
+        assert_eq!(
+            features.into_iter().collect::<Vec<_>>(),
+            ["foo_bar", "h753"]
+        );
+    }
+
+    #[test]
+    fn feature_combinations() {
+        let on = |f: &str| f == "foo-bar" || f == "baz";
+        assert!(all_enabled(&["foo-bar"], on));
+        assert!(all_enabled(&["foo-bar", "baz"], on));
+        assert!(!all_enabled(&["foo-bar", "nope"], on));
+        assert!(any_enabled(&["nope", "foo-bar"], on));
+        assert!(!any_enabled(&["nope"], on));
+        assert!(all_enabled(&[], on));
+        assert!(!any_enabled(&[], on));
+    }
+
+    #[test]
+    fn feature_env_var_name() {
+        assert_eq!(feature_env_var("h753"), "CARGO_FEATURE_H753");
+        assert_eq!(feature_env_var("foo-bar"), "CARGO_FEATURE_FOO_BAR");
+    }
+
+    #[test]
+    fn mpu_regions() {
+        use MProfile::*;
+        let regions = |profile, board, over| {
+            mpu_regions_for(profile, board, over).unwrap()
+        };
+        assert_eq!(regions(V6M, Some("stm32g070"), None), 8);
+        assert_eq!(regions(V7M, Some("gimlet-c"), None), 16);
+        assert_eq!(regions(V7M, Some("stm32f4-discovery"), None), 8);
+        assert_eq!(regions(V8MMain, None, None), 8);
+        assert_eq!(regions(V7M, Some("gimlet-c"), Some(12)), 12);
+        assert_eq!(regions(V6M, None, Some(4)), 4);
+
+        // An app with no `[config]` (so no `HUBRIS_APP_CONFIG`, as in this
+        // test process) gets the built-in count
+        assert_eq!(config_override::<u32>("mpu_regions").unwrap(), None);
+        let over = lookup_override(None, "mpu_regions").unwrap();
+        assert_eq!(regions(V7M, Some("gimlet-c"), over), 16);
+        let config = toml_value("mpu_regions = 12");
+        let over = lookup_override(Some(&config), "mpu_regions").unwrap();
+        assert_eq!(regions(V7M, Some("gimlet-c"), over), 12);
+        let config = toml_value("[net]\nport = 1");
+        let over = lookup_override(Some(&config), "mpu_regions").unwrap();
+        assert_eq!(regions(V7M, Some("gimlet-c"), over), 16);
+    }
+
+    #[test]
+    fn trustzone_opt_in() {
+        for (target, secure, tz) in [
+            ("thumbv8m.main-none-eabihf", Some("0"), true),
+            ("thumbv8m.base-none-eabi", Some("0"), true),
+            ("thumbv8m.main-none-eabihf", Some("1"), false),
+            ("thumbv8m.main-none-eabihf", None, false),
+            ("thumbv7em-none-eabihf", Some("0"), false),
+            ("thumbv6m-none-eabi", Some("0"), false),
+        ] {
+            assert_eq!(
+                has_trustzone(target, secure),
+                tz,
+                "{target} {secure:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn opt_levels() {
+        for (s, level) in [
+            ("0", OptLevel::Level(0)),
+            ("3", OptLevel::Level(3)),
+            ("s", OptLevel::Size),
+            ("z", OptLevel::MinSize),
+        ] {
+            assert_eq!(s.parse::<OptLevel>().unwrap(), level);
+            assert_eq!(level.to_string(), s);
+        }
+        assert!("4".parse::<OptLevel>().is_err());
+        assert!("fast".parse::<OptLevel>().is_err());
+    }
+
+    #[test]
+    fn build_assertions() {
+        assert!(build_assert(true, "fine").is_ok());
+        let err = build_assert(false, "stack too small").unwrap_err();
+        assert_eq!(err.to_string(), "stack too small");
+        assert!(build_assert_eq(4, 4, "same").is_ok());
+        let err = build_assert_eq(8, 16, "region count").unwrap_err();
+        assert_eq!(err.to_string(), "region count: `8` != `16`");
+    }
+
+    #[test]
+    fn defsym_is_hex() {
+        assert_eq!(
+            defsym_arg("_stack_base", 0x2000_0000),
+            "--defsym=_stack_base=0x20000000"
+        );
+        assert_eq!(defsym_arg("ZERO", 0), "--defsym=ZERO=0x0");
+    }
+
+    #[test]
+    fn dsp_from_target() {
+        for (target, dsp) in [
+            ("thumbv6m-none-eabi", false),
+            ("thumbv7m-none-eabi", false),
+            ("thumbv7em-none-eabi", true),
+            ("thumbv7em-none-eabihf", true),
+        ] {
+            assert_eq!(target_has_dsp(target), dsp, "{target}");
+            assert_eq!(
+                MProfile::from_target(target).unwrap() == MProfile::V7M,
+                target.starts_with("thumbv7")
+            );
+        }
+    }
+
+    #[test]
+    fn fpu_from_target() {
+        for (target, fpu) in [
+            ("thumbv6m-none-eabi", false),
+            ("thumbv7m-none-eabi", false),
+            ("thumbv7em-none-eabi", false),
+            ("thumbv7em-none-eabihf", true),
+            ("thumbv8m.base-none-eabi", false),
+            ("thumbv8m.main-none-eabi", false),
+            ("thumbv8m.main-none-eabihf", true),
+        ] {
+            assert_eq!(target_has_fpu(target), fpu, "{target}");
+        }
+    }
 }
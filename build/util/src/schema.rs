@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Validation of configuration against a JSON schema.
+//!
+//! This supports the subset of JSON Schema that's useful for describing
+//! configuration: `type`, `enum`, `const`, `properties`, `required`,
+//! `additionalProperties`, `items`, `minItems`/`maxItems`,
+//! `minLength`/`maxLength`, and `minimum`/`maximum`, plus annotations like
+//! `title` and `description`.  A schema using any other keyword (e.g.
+//! `pattern`, `oneOf`, or `$ref`) is rejected, rather than having that
+//! keyword silently ignored, which would let invalid config through.
+//!
+//! (This isn't delegated to a JSON Schema crate because there isn't one in
+//! our lockfile, and this small subset doesn't justify adding one, along with
+//! its dependencies, to every build script that validates its config.)
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// Keywords that `check` implements
+const SUPPORTED: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+];
+
+/// Keywords that don't affect validation
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+/// Validates `value` against `schema`, returning a list of every violation
+/// (each prefixed with the JSON pointer of the failing value), or an error if
+/// the schema uses a keyword that isn't supported.
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<Vec<String>> {
+    check_supported(schema, "")?;
+    let mut errors = vec![];
+    check(schema, value, "", &mut errors);
+    Ok(errors)
+}
+
+/// Checks that every keyword in `schema` (and its subschemas) is supported;
+/// `path` is the JSON pointer of `schema` within the whole schema.
+fn check_supported(schema: &Value, path: &str) -> Result<()> {
+    let Value::Object(schema) = schema else {
+        return Ok(());
+    };
+    for (key, v) in schema {
+        if ANNOTATIONS.contains(&key.as_str()) {
+            continue;
+        }
+        if !SUPPORTED.contains(&key.as_str()) {
+            bail!(
+                "unsupported schema keyword `{}` at {}; only {} are supported",
+                key,
+                at(path),
+                SUPPORTED.join(", ")
+            );
+        }
+        match (key.as_str(), v) {
+            ("properties", Value::Object(props)) => {
+                for (name, s) in props {
+                    check_supported(
+                        s,
+                        &format!("{}/properties/{}", path, name),
+                    )?;
+                }
+            }
+            ("additionalProperties" | "items", s) => {
+                check_supported(s, &format!("{}/{}", path, key))?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn check(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            errors.push(format!("{}: no value is allowed here", at(path)));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => {
+            errors.push(format!("{}: schema is not an object", at(path)));
+            return;
+        }
+    };
+    let mut fail = |msg: String| errors.push(format!("{}: {}", at(path), msg));
+
+    if let Some(ty) = schema.get("type") {
+        let types: Vec<&str> = match ty {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(a) => a.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.iter().any(|t| has_type(value, t)) {
+            fail(format!(
+                "expected {}, found {}",
+                types.join(" or "),
+                type_name(value)
+            ));
+            // Everything else assumes the type is right
+            return;
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            fail(format!(
+                "{} is not one of {}",
+                value,
+                Value::from(options.clone())
+            ));
+        }
+    }
+    if let Some(c) = schema.get("const") {
+        if c != value {
+            fail(format!("expected {}, found {}", c, value));
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            let props = schema.get("properties").and_then(Value::as_object);
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !map.contains_key(key) {
+                        fail(format!("missing required field `{}`", key));
+                    }
+                }
+            }
+            for (key, v) in map {
+                let child = format!("{}/{}", path, key);
+                match props.and_then(|p| p.get(key)) {
+                    Some(s) => check(s, v, &child, errors),
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => errors.push(format!(
+                            "{}: unknown field `{}`",
+                            at(path),
+                            key
+                        )),
+                        Some(s) => check(s, v, &child, errors),
+                        None => (),
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_len(schema, "Items", items.len(), &mut fail);
+            if let Some(s) = schema.get("items") {
+                for (i, v) in items.iter().enumerate() {
+                    check(s, v, &format!("{}/{}", path, i), errors);
+                }
+            }
+        }
+        Value::String(s) => {
+            check_len(schema, "Length", s.chars().count(), &mut fail);
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(f64::NAN);
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                if n < min {
+                    fail(format!("{} is less than the minimum of {}", n, min));
+                }
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                if n > max {
+                    fail(format!("{} is more than the maximum of {}", n, max));
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Checks the `min<kind>` and `max<kind>` keywords against a length
+fn check_len(
+    schema: &serde_json::Map<String, Value>,
+    kind: &str,
+    len: usize,
+    fail: &mut impl FnMut(String),
+) {
+    let len = len as u64;
+    let get = |k: String| schema.get(&k).and_then(Value::as_u64);
+    if let Some(min) = get(format!("min{}", kind)) {
+        if len < min {
+            fail(format!(
+                "length {} is less than the minimum of {}",
+                len, min
+            ));
+        }
+    }
+    if let Some(max) = get(format!("max{}", kind)) {
+        if len > max {
+            fail(format!(
+                "length {} is more than the maximum of {}",
+                len, max
+            ));
+        }
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        // JSON Schema counts any number with no fractional part (e.g. `1.0`)
+        // as an integer
+        "integer" => {
+            value.is_i64()
+                || value.is_u64()
+                || value.as_f64().map_or(false, |n| n.fract() == 0.0)
+        }
+        _ => type_name(value) == ty || (ty == "number" && value.is_number()),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Formats a JSON pointer for an error message
+fn at(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["bus"],
+            "additionalProperties": false,
+            "properties": {
+                "bus": { "type": "string", "enum": ["i2c1", "i2c2"] },
+                "speed": { "type": "integer", "minimum": 1, "maximum": 400 },
+                "pins": {
+                    "type": "array",
+                    "maxItems": 2,
+                    "items": { "type": "integer" }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn valid() {
+        let value = json!({ "bus": "i2c1", "speed": 100, "pins": [3, 4] });
+        assert!(validate(&schema(), &value).unwrap().is_empty());
+    }
+
+    #[test]
+    fn violations() {
+        let value = json!({
+            "bus": "i2c3",
+            "sped": 100,
+            "pins": [3, "4", 5],
+        });
+        assert_eq!(
+            validate(&schema(), &value).unwrap(),
+            [
+                "/bus: \"i2c3\" is not one of [\"i2c1\",\"i2c2\"]",
+                "/pins: length 3 is more than the maximum of 2",
+                "/pins/1: expected integer, found string",
+                "/: unknown field `sped`",
+            ]
+        );
+    }
+
+    #[test]
+    fn integral_floats() {
+        let value = json!({ "bus": "i2c1", "speed": 100.0 });
+        assert!(validate(&schema(), &value).unwrap().is_empty());
+        let value = json!({ "bus": "i2c1", "speed": 100.5 });
+        assert_eq!(
+            validate(&schema(), &value).unwrap(),
+            ["/speed: expected integer, found number"]
+        );
+    }
+
+    #[test]
+    fn missing_and_range() {
+        let value = json!({ "speed": 1000 });
+        assert_eq!(
+            validate(&schema(), &value).unwrap(),
+            [
+                "/: missing required field `bus`",
+                "/speed: 1000 is more than the maximum of 400",
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_keywords() {
+        let schema = json!({
+            "title": "SPI config",
+            "properties": {
+                "bus": { "type": "string", "pattern": "^spi[0-9]$" }
+            }
+        });
+        // This fails even though the value would be fine, since the schema
+        // can't be fully checked
+        let err = validate(&schema, &json!({})).unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "unsupported schema keyword `pattern` at /properties/bus;"
+            ),
+            "{err}"
+        );
+        for keyword in ["oneOf", "anyOf", "$ref"] {
+            let schema = json!({ "items": { keyword: [] } });
+            assert!(validate(&schema, &json!([])).is_err(), "{keyword}");
+        }
+    }
+}
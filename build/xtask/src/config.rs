@@ -148,6 +148,9 @@ impl Config {
         if toml.tasks.contains_key("kernel") {
             bail!("'kernel' is reserved and cannot be used as a task name");
         }
+        for (name, task) in &toml.tasks {
+            check_notifications(name, &task.notifications)?;
+        }
 
         // The app.toml must include a `chip` key, which defines the peripheral
         // register map in a separate file.  We load it then accumulate that
@@ -252,6 +255,7 @@ impl Config {
         verbose: bool,
         crate_name: &str,
         features: &[String],
+        image_kind: &str,
         sysroot: Option<&'a Path>,
     ) -> BuildConfig<'a> {
         let mut args = vec![
@@ -289,6 +293,9 @@ impl Config {
         );
         env.insert("HUBRIS_BUILD_EPOCH".to_string(), format!("{}", self.epoch));
         env.insert("HUBRIS_BOARD".to_string(), self.board.to_string());
+        // Whether this is the kernel or a task, for build scripts shared
+        // between them (read by `build_util::image_kind`)
+        env.insert("HUBRIS_IMAGE_KIND".to_string(), image_kind.to_string());
         env.insert(
             "HUBRIS_APP_TOML".to_string(),
             app_toml_path.to_str().unwrap().to_string(),
@@ -357,6 +364,7 @@ impl Config {
             verbose,
             &self.kernel.name,
             &self.kernel.features,
+            "kernel",
             sysroot,
         );
         for (var, value) in extra_env {
@@ -379,6 +387,7 @@ impl Config {
             verbose,
             &task_toml.name,
             &task_toml.features,
+            "task",
             sysroot,
         );
 
@@ -677,6 +686,22 @@ pub struct Output {
     pub dma: bool,
 }
 
+/// Checks a task's notification names, which are assigned bits in order
+fn check_notifications(task: &str, notifications: &[String]) -> Result<()> {
+    if notifications.len() > 32 {
+        bail!(
+            "task {task} declares {} notifications, but only has 32 bits",
+            notifications.len()
+        );
+    }
+    for (i, n) in notifications.iter().enumerate() {
+        if notifications[..i].contains(n) {
+            bail!("task {task} declares notification {n} more than once");
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Task {
@@ -692,7 +717,9 @@ pub struct Task {
     #[serde(default)]
     pub features: Vec<String>,
     #[serde(default)]
-    pub interrupts: IndexMap<String, u32>,
+    pub interrupts: IndexMap<String, Notification>,
+    #[serde(default)]
+    pub notifications: Vec<String>,
     #[serde(default)]
     pub sections: IndexMap<String, String>,
     #[serde(default, deserialize_with = "deserialize_task_slot")]
@@ -703,6 +730,36 @@ pub struct Task {
     pub uses_secure_entry: bool,
 }
 
+impl Task {
+    /// Resolves the notification posted by one of this task's interrupts to
+    /// a mask
+    pub fn notification_mask(
+        &self,
+        notification: &Notification,
+    ) -> Result<u32> {
+        match notification {
+            Notification::Mask(mask) => Ok(*mask),
+            Notification::Name(n) => {
+                let Some(bit) = self.notifications.iter().position(|d| d == n)
+                else {
+                    bail!("no notification named {n} is declared");
+                };
+                Ok(1 << bit)
+            }
+        }
+    }
+}
+
+/// The notification posted to a task by one of its `interrupts`: either a
+/// mask, or the name of one of the task's `notifications`, which are assigned
+/// bits in the order they're declared.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Notification {
+    Mask(u32),
+    Name(String),
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Peripheral {
@@ -1930,7 +1930,10 @@ pub fn make_kconfig(
         });
 
         // Interrupts.
-        for (irq_str, &notification) in &task.interrupts {
+        for (irq_str, notification) in &task.interrupts {
+            let notification = task
+                .notification_mask(notification)
+                .with_context(|| format!("task {}: IRQ {}", name, irq_str))?;
             // The irq_str can be either a base-ten number, or a reference to a
             // peripheral. Distinguish them based on whether it parses as an
             // integer.